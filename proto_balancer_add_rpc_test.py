@@ -8,7 +8,7 @@ def add_rpc():
         "chain_id": 10,
         "rpc_location": "External",
     }
-    response = requests.post("http://127.0.0.1:3003/10", json=payload)
+    response = requests.post("http://127.0.0.1:3003/admin/rpcs", json=payload)
     result = response.json()
     return result
 
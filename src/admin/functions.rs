@@ -0,0 +1,442 @@
+use crate::{
+    admin::types::{
+        AdminRequest,
+        SetAlgoRequest,
+        SetLogLevelRequest,
+    },
+    cache::functions::get_cache_stats,
+    config::{
+        functions::{
+            apply_log_level,
+            persist_rpc,
+            persist_rpc_removal,
+            persist_rpc_update,
+        },
+        types::Settings,
+    },
+    metrics::functions::{
+        remove_upstream_metrics,
+        upstream_label,
+    },
+    rpc::{
+        errors::{
+            ApplicationError,
+            ErrorCode,
+            JsonRpcErrorResponse,
+        },
+        functions::{
+            incoming_to_value,
+            with_json_content_type,
+        },
+        types::{
+            AddRpcRequest,
+            HeadSource,
+            JsonRpcResponse,
+            Rpc,
+            RpcInfo,
+            RpcLocation,
+            UpdateRpcRequest,
+        },
+    },
+    routing::functions::is_valid_tag,
+    sort::types::Algo,
+    stats::functions::get_stats,
+    head::functions::start_head_tracking,
+};
+
+use hyper::header::AUTHORIZATION;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, LevelFilter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+
+pub async fn serve_admin(listener: TcpListener, rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>) {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Error accepting admin connection: {}", e);
+                continue;
+            }
+        };
+        let io = TokioIo::new(stream);
+        let rpc_list_clone = rpc_list.clone();
+        let config_clone = config.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(|req| {
+                        admin_request(req, remote_addr, rpc_list_clone.clone(), config_clone.clone())
+                    }),
+                )
+                .await
+            {
+                error!("Error serving admin connection: {}", err);
+            }
+        });
+    }
+}
+
+pub fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+pub fn parse_admin_request(method: &Method, path: &str) -> Option<AdminRequest> {
+    let path = path.strip_prefix("/admin").unwrap_or(path);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["get_stats"]) => Some(AdminRequest::GetStats),
+        (&Method::GET, ["cache"]) => Some(AdminRequest::GetCacheStats),
+        (&Method::GET, ["rpcs"]) => Some(AdminRequest::ListRpcs),
+        (&Method::POST, ["rpcs"]) => Some(AdminRequest::AddRpc),
+        (&Method::PATCH, ["rpcs", rpc_id]) => rpc_id.parse().ok().map(AdminRequest::UpdateRpc),
+        (&Method::DELETE, ["rpcs", rpc_id]) => rpc_id.parse().ok().map(AdminRequest::RemoveRpc),
+        (&Method::PUT, ["algo"]) => Some(AdminRequest::SetAlgo),
+        (&Method::PUT, ["log_level"]) => Some(AdminRequest::SetLogLevel),
+        _ => None,
+    }
+}
+
+pub async fn admin_request(request: Request<hyper::body::Incoming>, remote_addr: SocketAddr,
+                           rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    let response = route_admin_request(request, remote_addr, rpc_list, config).await?;
+    Ok(with_json_content_type(response))
+}
+
+async fn route_admin_request(request: Request<hyper::body::Incoming>, remote_addr: SocketAddr,
+                             rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    if let Err(app_error) = authorize(&request, remote_addr, &config).await {
+        let json_response = JsonRpcErrorResponse::from(app_error);
+        error!("Error: {} from {}", json_response.error.format_error().as_str(), remote_addr);
+        return Ok(json_response.to_response());
+    }
+
+    let admin_request = match parse_admin_request(request.method(), request.uri().path()) {
+        Some(admin_request) => admin_request,
+        None => {
+            let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+                ErrorCode::MethodNotFound,
+                format!("Unknown admin request: {} {}", request.method(), request.uri().path()),
+            ));
+            error!("Error: {}", json_response.error.format_error().as_str());
+            return Ok(json_response.to_response());
+        }
+    };
+    info!("Admin request {} {} from {}", request.method(), request.uri().path(), remote_addr);
+
+    match admin_request {
+        AdminRequest::GetStats => get_stats(rpc_list, request.uri().query()),
+        AdminRequest::GetCacheStats => get_cache_stats(),
+        AdminRequest::ListRpcs => {
+            let rpc_infos: Vec<RpcInfo> = rpc_list.lock().unwrap().iter().map(RpcInfo::from).collect();
+            Ok(Response::new(serde_json::to_string(&rpc_infos).unwrap()))
+        },
+        AdminRequest::AddRpc => {
+            let json_value = incoming_to_value(request).await?;
+            if let Ok(add_rpc_request) = serde_json::from_value::<AddRpcRequest>(json_value.clone()) {
+                add_rpc(rpc_list, add_rpc_request, config).await
+            } else if let Ok(add_rpc_requests) = serde_json::from_value::<Vec<AddRpcRequest>>(json_value) {
+                let mut responses = Vec::new();
+                for add_rpc_request in add_rpc_requests {
+                    let response = add_rpc(rpc_list.clone(), add_rpc_request, config.clone()).await?;
+                    responses.push(response.into_body());
+                }
+                Ok(Response::new(format!("[{}]", responses.join(","))))
+            } else {
+                Ok(bad_request("Invalid add rpc request".to_string()))
+            }
+        },
+        AdminRequest::UpdateRpc(rpc_id) => {
+            let json_value = incoming_to_value(request).await?;
+            match serde_json::from_value::<UpdateRpcRequest>(json_value) {
+                Ok(update_rpc_request) => update_rpc(rpc_list, rpc_id, update_rpc_request, config).await,
+                Err(e) => Ok(bad_request(format!("Invalid update rpc request: {}", e))),
+            }
+        },
+        AdminRequest::RemoveRpc(rpc_id) => remove_rpc(rpc_list, rpc_id, config).await,
+        AdminRequest::SetAlgo => {
+            let json_value = incoming_to_value(request).await?;
+            match serde_json::from_value::<SetAlgoRequest>(json_value) {
+                Ok(set_algo_request) => set_algo(set_algo_request, config).await,
+                Err(e) => Ok(bad_request(format!("Invalid set algo request: {}", e))),
+            }
+        },
+        AdminRequest::SetLogLevel => {
+            let json_value = incoming_to_value(request).await?;
+            match serde_json::from_value::<SetLogLevelRequest>(json_value) {
+                Ok(set_log_level_request) => set_log_level(set_log_level_request, config).await,
+                Err(e) => Ok(bad_request(format!("Invalid set log level request: {}", e))),
+            }
+        },
+    }
+}
+
+pub async fn authorize(request: &Request<hyper::body::Incoming>, remote_addr: SocketAddr,
+                       config: &Arc<RwLock<Settings>>) -> Result<(), ApplicationError> {
+    let (admin_token, admin_allowed_ips) = {
+        let config_guard = config.read().await;
+        (config_guard.admin_token.clone(), config_guard.admin_allowed_ips.clone())
+    };
+
+    // Loopback clients may be a local proxy forwarding public requests, the admin api stays closed
+    // until a token or an allow list is configured
+    if admin_token.is_none() && admin_allowed_ips.is_empty() {
+        return Err(ApplicationError::new(
+            ErrorCode::Forbidden,
+            "Admin API is disabled, configure an admin token or allowed_ips".to_string(),
+        ));
+    }
+    let ip_allowed = admin_allowed_ips.is_empty() || admin_allowed_ips.contains(&remote_addr.ip());
+    if !ip_allowed {
+        return Err(ApplicationError::new(
+            ErrorCode::Forbidden,
+            "Admin API is not allowed from this address".to_string(),
+        ));
+    }
+
+    if let Some(admin_token) = admin_token {
+        let bearer_token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        let token_valid = bearer_token.is_some_and(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()));
+        if !token_valid {
+            return Err(ApplicationError::new(
+                ErrorCode::Unauthorized,
+                "Missing or invalid admin bearer token".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // Compare every byte so the time taken does not leak the matching prefix
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub async fn add_rpc(rpc_list: Arc<Mutex<Vec<Rpc>>>, add_rpc_request: AddRpcRequest,
+                     config: Arc<RwLock<Settings>>) -> Result<Response<String>, hyper::Error> {
+    let (stat_vec_size, persist, config_path) = {
+        let config_guard = config.read().await;
+        (config_guard.stats_vec_size, config_guard.persist, config_guard.config_path.clone())
+    };
+    let rpc_location = match RpcLocation::from_str(add_rpc_request.rpc_location.as_str()) {
+        Ok(rpc_location) => rpc_location,
+        Err(_) => return Ok(bad_request("Invalid rpc_location (i.e. Local, External)".to_string())),
+    };
+    let head_source = match add_rpc_request.head_source.as_deref().map(HeadSource::from_str) {
+        Some(Ok(HeadSource::Ws)) if add_rpc_request.ws_url.is_none() => {
+            return Ok(bad_request("head_source ws requires a ws_url".to_string()));
+        },
+        Some(Ok(head_source)) => Some(head_source),
+        Some(Err(_)) => return Ok(bad_request("Invalid head_source (i.e. ws, poll)".to_string())),
+        None => None,
+    };
+    if add_rpc_request.chain_id < 1 {
+        return Ok(bad_request("chain_id must be at least 1".to_string()));
+    }
+    if add_rpc_request.max_batch_size == Some(0) {
+        return Ok(bad_request("max_batch_size must be at least 1".to_string()));
+    }
+    if add_rpc_request.tags.iter().any(|tag| !is_valid_tag(tag)) {
+        return Ok(bad_request("Invalid tags (i.e. [\"archive\", \"trace\"])".to_string()));
+    }
+    let add_rpc_request_clone = add_rpc_request.clone();
+
+    let mut rpc = Rpc::new(add_rpc_request.url,
+                       add_rpc_request.ws_url,
+                       add_rpc_request.chain_id,
+                       rpc_location,
+                       stat_vec_size).await;
+    if let Some(head_source) = head_source {
+        rpc.head_source = head_source;
+    }
+    rpc.max_batch_size = add_rpc_request.max_batch_size;
+    rpc.tags = add_rpc_request.tags;
+    rpc.admin_override = !persist;
+    // The url is checked and the rpc pushed under the same lock, so concurrent requests cannot both add it
+    let rpc_clone =
+        {
+            let mut rpc_guard = rpc_list.lock().unwrap();
+            if rpc_guard.iter().any(|known| known.url == rpc.url) {
+                None
+            } else {
+                let rpc_result = rpc.clone();
+                rpc_guard.push(rpc);
+                debug!("Rpc_guard after add_rpc : {:?}", rpc_guard);
+                Some(rpc_result)
+            }
+        };
+    let rpc_clone = match rpc_clone {
+        Some(rpc_clone) => rpc_clone,
+        None => {
+            let response = JsonRpcResponse::from("Rpc already added".to_string());
+            info!("{}: {}", response.result, add_rpc_request_clone.url);
+            return Ok(Response::new(response.to_json()));
+        },
+    };
+    start_head_tracking(rpc_list.clone(), rpc_clone.id, config.clone()).await;
+
+    if persist {
+        if let Err(e) = persist_rpc(&config_path, &add_rpc_request_clone).await {
+            let response = JsonRpcResponse::from("Rpc added successfully but could not be persisted".to_string());
+            error!("{}: {}: {}", response.result, add_rpc_request_clone.url, e);
+            return Ok(Response::new(response.to_json()));
+        }
+    }
+
+    let response = JsonRpcResponse::from("Rpc added successfully".to_string());
+    info!("{}: {}", response.result, add_rpc_request_clone.url);
+    Ok(Response::new(response.to_json()))
+}
+
+pub async fn remove_rpc(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize,
+                        config: Arc<RwLock<Settings>>) -> Result<Response<String>, hyper::Error> {
+    let removed_rpc = {
+        let mut rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard
+            .iter()
+            .position(|rpc| rpc.id == rpc_id)
+            .map(|index| rpc_guard.remove(index))
+    };
+
+    let removed_rpc = match removed_rpc {
+        Some(removed_rpc) => removed_rpc,
+        None => return Ok(rpc_not_found(rpc_id)),
+    };
+    // Stop the websocket listener of the removed rpc
+    removed_rpc.ws_shutdown.notify_one();
+    remove_upstream_metrics(removed_rpc.chain_id, &upstream_label(&removed_rpc));
+
+    let (persist, config_path) = {
+        let config_guard = config.read().await;
+        (config_guard.persist, config_guard.config_path.clone())
+    };
+    if persist {
+        if let Err(e) = persist_rpc_removal(&config_path, &removed_rpc.url).await {
+            let response = JsonRpcResponse::from("Rpc removed successfully but could not be persisted".to_string());
+            error!("{}: {}: {}", response.result, removed_rpc.url, e);
+            return Ok(Response::new(response.to_json()));
+        }
+    }
+
+    let response = JsonRpcResponse::from("Rpc removed successfully".to_string());
+    info!("{}: {}", response.result, removed_rpc.url);
+    Ok(Response::new(response.to_json()))
+}
+
+pub async fn update_rpc(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize, update_rpc_request: UpdateRpcRequest,
+                        config: Arc<RwLock<Settings>>) -> Result<Response<String>, hyper::Error> {
+    let rpc_location = match update_rpc_request.rpc_location.as_deref().map(RpcLocation::from_str) {
+        Some(Ok(rpc_location)) => Some(rpc_location),
+        Some(Err(_)) => return Ok(bad_request("Invalid rpc_location (i.e. Local, External)".to_string())),
+        None => None,
+    };
+    if update_rpc_request.chain_id == Some(0) {
+        return Ok(bad_request("chain_id must be at least 1".to_string()));
+    }
+    if update_rpc_request.tags.iter().flatten().any(|tag| !is_valid_tag(tag)) {
+        return Ok(bad_request("Invalid tags (i.e. [\"archive\", \"trace\"])".to_string()));
+    }
+    let (persist, config_path) = {
+        let config_guard = config.read().await;
+        (config_guard.persist, config_guard.config_path.clone())
+    };
+
+    let updated_rpc = {
+        let mut rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id).map(|rpc| {
+            rpc.admin_override |= !persist;
+            if let Some(enabled) = update_rpc_request.enabled {
+                rpc.enabled = enabled;
+            }
+            if let Some(rpc_location) = rpc_location {
+                rpc.rpc_location = rpc_location;
+            }
+            if let Some(chain_id) = update_rpc_request.chain_id {
+                // The series of the previous chain are no longer updated
+                if chain_id != rpc.chain_id {
+                    remove_upstream_metrics(rpc.chain_id, &upstream_label(rpc));
+                }
+                rpc.set_chain_id(chain_id);
+            }
+            if let Some(tags) = update_rpc_request.tags {
+                rpc.tags = tags;
+            }
+            rpc.clone()
+        })
+    };
+
+    let updated_rpc = match updated_rpc {
+        Some(updated_rpc) => updated_rpc,
+        None => return Ok(rpc_not_found(rpc_id)),
+    };
+
+    if persist {
+        if let Err(e) = persist_rpc_update(&config_path, &updated_rpc).await {
+            let response = JsonRpcResponse::from("Rpc updated successfully but could not be persisted".to_string());
+            error!("{}: {}: {}", response.result, updated_rpc.url, e);
+            return Ok(Response::new(response.to_json()));
+        }
+    }
+
+    let response = JsonRpcResponse::from("Rpc updated successfully".to_string());
+    info!("{}: {} {:?}", response.result, updated_rpc.url, RpcInfo::from(&updated_rpc));
+    Ok(Response::new(response.to_json()))
+}
+
+fn rpc_not_found(rpc_id: usize) -> Response<String> {
+    let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+        ErrorCode::NotFound,
+        format!("No RPC node found with id {}", rpc_id),
+    ));
+    error!("Error: {}", json_response.error.format_error().as_str());
+    json_response.to_response()
+}
+
+pub async fn set_algo(set_algo_request: SetAlgoRequest,
+                      config: Arc<RwLock<Settings>>) -> Result<Response<String>, hyper::Error> {
+    let algo = match Algo::from_str(&set_algo_request.algo) {
+        Ok(algo) => algo,
+        Err(_) => return Ok(bad_request(format!("Invalid algo: {}", set_algo_request.algo))),
+    };
+    config.write().await.algo = algo.clone();
+
+    let response = JsonRpcResponse::from("Algo updated successfully".to_string());
+    info!("{}: {:?}", response.result, algo);
+    Ok(Response::new(response.to_json()))
+}
+
+pub async fn set_log_level(set_log_level_request: SetLogLevelRequest,
+                           config: Arc<RwLock<Settings>>) -> Result<Response<String>, hyper::Error> {
+    let log_level = match LevelFilter::from_str(&set_log_level_request.log_level) {
+        Ok(log_level) => log_level,
+        Err(_) => return Ok(bad_request(format!("Invalid log_level: {}", set_log_level_request.log_level))),
+    };
+    config.write().await.log_level = log_level.to_string().to_lowercase();
+    apply_log_level(log_level);
+
+    let response = JsonRpcResponse::from("Log level updated successfully".to_string());
+    info!("{}: {}", response.result, log_level);
+    Ok(Response::new(response.to_json()))
+}
+
+fn bad_request(message: String) -> Response<String> {
+    let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+        ErrorCode::BadRequest,
+        message,
+    ));
+    error!("Error: {}", json_response.error.format_error().as_str());
+    json_response.to_response()
+}
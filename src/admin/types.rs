@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetAlgoRequest {
+    pub algo: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetLogLevelRequest {
+    pub log_level: String,
+}
+
+pub enum AdminRequest {
+    GetStats,
+    GetCacheStats,
+    ListRpcs,
+    AddRpc,
+    UpdateRpc(usize),
+    RemoveRpc(usize),
+    SetAlgo,
+    SetLogLevel,
+}
@@ -0,0 +1,279 @@
+use crate::{
+    archive::types::{
+        HistoryDepth,
+        HistoryRank,
+        LearnedHistory,
+        RequestedBlock,
+        StateDepth,
+        ARCHIVE_PROBE_BLOCK,
+        DEFAULT_PRUNED_HISTORY_DEPTH,
+        LEARNED_HISTORY_TTL_MS,
+        MIN_LEARNED_HISTORY_DEPTH,
+        MISSING_STATE_ERROR_MESSAGES,
+        STATE_METHODS,
+    },
+    rpc::{
+        functions::{
+            rpc_host,
+            send_request,
+        },
+        types::Rpc,
+    },
+};
+
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+pub fn requested_block(json_value: &Value, rpc_list: &[Rpc]) -> Option<RequestedBlock> {
+    // Block tags are served by every node, block numbers and hashes may go back in history
+    let method = json_value.get("method")?.as_str()?;
+    let (_, position) = STATE_METHODS.iter().find(|(state_method, _)| *state_method == method)?;
+    let block = json_value.get("params")?.get(*position)?;
+    // EIP-1898 block parameter, i.e. {"blockNumber": "0x10"} or {"blockHash": "0x..."}
+    let block = match block {
+        Value::Object(block) => match block.get("blockHash") {
+            Some(hash) => return Some(resolve_block_hash(hash.as_str()?, rpc_list)),
+            None => block.get("blockNumber")?,
+        },
+        block => block,
+    };
+    match block.as_str()? {
+        "earliest" => Some(RequestedBlock::Number(0)),
+        // Some nodes also take a bare block hash
+        hash if hash.len() == 66 => Some(resolve_block_hash(hash, rpc_list)),
+        block => u64::from_str_radix(block.strip_prefix("0x")?, 16).ok().map(RequestedBlock::Number),
+    }
+}
+
+pub fn resolve_block_hash(hash: &str, rpc_list: &[Rpc]) -> RequestedBlock {
+    // Only the recent blocks reported by the rpcs tell the number of a hash
+    rpc_list
+        .iter()
+        .flat_map(|rpc| rpc.recent_blocks.iter())
+        .find(|block| block.hash.eq_ignore_ascii_case(hash))
+        .map_or(RequestedBlock::UnknownHash, |block| RequestedBlock::Number(block.number))
+}
+
+pub fn requested_depth(chain_head: u64, requested_block: Option<RequestedBlock>) -> Option<StateDepth> {
+    // Blocks ahead of the tracked head are as recent as it gets
+    requested_block.map(|block| match block {
+        RequestedBlock::Number(number) => StateDepth::Blocks(chain_head.saturating_sub(number)),
+        RequestedBlock::UnknownHash => StateDepth::Unknown,
+    })
+}
+
+pub fn history_of(rpc: &Rpc) -> HistoryDepth {
+    // A learned history overrides the configured or probed one until it expires
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    match rpc.learned_history {
+        Some(learned_history) if now < learned_history.expires_at => HistoryDepth::Blocks(learned_history.blocks),
+        _ => rpc.history_depth.clone(),
+    }
+}
+
+pub fn history_rank(rpc: &Rpc, depth: Option<StateDepth>) -> HistoryRank {
+    let history_depth = history_of(rpc);
+    let depth = match depth {
+        Some(StateDepth::Blocks(depth)) => depth,
+        // The depth of an unknown hash is never enough to exclude a node
+        Some(StateDepth::Unknown) if history_depth == HistoryDepth::Archive => return HistoryRank::Keeps,
+        Some(StateDepth::Unknown) => return HistoryRank::Unverified,
+        None => return HistoryRank::Keeps,
+    };
+    match history_depth.keeps(depth) {
+        Some(true) => HistoryRank::Keeps,
+        Some(false) => HistoryRank::Missing,
+        None if depth <= DEFAULT_PRUNED_HISTORY_DEPTH => HistoryRank::Keeps,
+        None => HistoryRank::Unverified,
+    }
+}
+
+pub fn keeps_state(rpc: &Rpc, depth: Option<StateDepth>) -> bool {
+    // Known to keep the state of the block, for an unknown hash or without a block only archive nodes are
+    let depth = match depth {
+        Some(StateDepth::Blocks(depth)) => depth,
+        _ => u64::MAX,
+    };
+    history_of(rpc).keeps(depth) == Some(true)
+}
+
+pub fn prefer_history(mut sorted_rpc_list: Vec<&Rpc>, depth: Option<StateDepth>) -> Vec<&Rpc> {
+    // The rpcs known to keep the block go before the unverified ones, each group keeps the order of the sort
+    sorted_rpc_list.sort_by_key(|rpc| history_rank(rpc, depth));
+    sorted_rpc_list
+}
+
+pub fn is_missing_state(entry: &Value) -> bool {
+    let message = entry
+        .get("error")
+        .and_then(|error| error.get("message"))
+        .and_then(|message| message.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    MISSING_STATE_ERROR_MESSAGES.iter().any(|fragment| message.contains(fragment))
+}
+
+pub fn record_missing_state(rpc_list: &Arc<Mutex<Vec<Rpc>>>, rpc_id: usize, requested_block: Option<RequestedBlock>) {
+    // The rpc does not have the state of the block, so it keeps fewer blocks than the block is behind its own head
+    let block = match requested_block {
+        Some(RequestedBlock::Number(block)) => block,
+        _ => return,
+    };
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    let rpc = match rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id && rpc.last_block > 0) {
+        Some(rpc) => rpc,
+        None => return,
+    };
+    let depth = rpc.last_block.saturating_sub(block);
+    match history_of(rpc) {
+        HistoryDepth::Archive => warn!("Archive RPC {} is missing the state {} blocks behind its head", rpc_host(&rpc.url), depth),
+        HistoryDepth::Blocks(blocks) if blocks < depth => {},
+        _ if depth <= MIN_LEARNED_HISTORY_DEPTH => {
+            debug!("RPC {} is missing the state {} blocks behind its head, not learned", rpc_host(&rpc.url), depth);
+        },
+        _ => {
+            rpc.learned_history = Some(LearnedHistory {
+                blocks: depth - 1,
+                expires_at: chrono::Utc::now().timestamp_millis() as u64 + LEARNED_HISTORY_TTL_MS,
+            });
+            info!("RPC {} keeps the state of fewer than {} blocks", rpc_host(&rpc.url), depth);
+        },
+    }
+}
+
+pub async fn probe_history(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize) {
+    // Only the rpcs without a configured history are probed
+    let url = {
+        let rpc_guard = rpc_list.lock().unwrap();
+        match rpc_guard.iter().find(|rpc| rpc.id == rpc_id && rpc.history_depth == HistoryDepth::Unknown) {
+            Some(rpc) => rpc.url.clone(),
+            None => return,
+        }
+    };
+
+    // Reading the state of an early block succeeds on archive nodes only
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": "eth_getBalance",
+        "params": ["0x0000000000000000000000000000000000000000", ARCHIVE_PROBE_BLOCK],
+        "id": 1,
+    });
+    let history_depth = match send_request(url.clone(), request).await {
+        Ok(response) => match serde_json::from_str::<Value>(&response) {
+            Ok(entry) if entry.get("result").is_some_and(|result| !result.is_null()) => HistoryDepth::Archive,
+            // Not an archive node, how far back it goes is only learned from the requests
+            Ok(entry) if is_missing_state(&entry) => HistoryDepth::Pruned,
+            _ => {
+                debug!("History probe of {} is inconclusive: {}", rpc_host(&url), response);
+                return;
+            },
+        },
+        Err(app_error) => {
+            debug!("History probe of {} failed: {}", rpc_host(&url), app_error.format_error());
+            return;
+        },
+    };
+
+    // A history configured meanwhile is kept
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    if let Some(rpc) = rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id && rpc.history_depth == HistoryDepth::Unknown) {
+        info!("History of RPC {}: {}", rpc_host(&rpc.url), history_depth);
+        rpc.history_depth = history_depth;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::head::types::BlockHeader;
+
+    fn state_read(block: Value) -> Value {
+        json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": ["0xabc", block], "id": 1})
+    }
+
+    fn rpc_with_head(id: usize, last_block: u64, history_depth: HistoryDepth) -> Rpc {
+        Rpc { id, last_block, history_depth, ..Rpc::default() }
+    }
+
+    #[test]
+    fn requested_block_reads_numbers_and_tags() {
+        assert_eq!(requested_block(&state_read(json!("0x10")), &[]), Some(RequestedBlock::Number(16)));
+        assert_eq!(requested_block(&state_read(json!("earliest")), &[]), Some(RequestedBlock::Number(0)));
+        assert_eq!(requested_block(&state_read(json!({"blockNumber": "0x20"})), &[]), Some(RequestedBlock::Number(32)));
+        assert_eq!(requested_block(&state_read(json!("latest")), &[]), None);
+        assert_eq!(requested_block(&state_read(json!("pending")), &[]), None);
+    }
+
+    #[test]
+    fn requested_block_uses_the_block_position_of_the_method() {
+        let storage_at = json!({"jsonrpc": "2.0", "method": "eth_getStorageAt", "params": ["0xabc", "0x0", "0x5"], "id": 1});
+        assert_eq!(requested_block(&storage_at, &[]), Some(RequestedBlock::Number(5)));
+        let block_number = json!({"jsonrpc": "2.0", "method": "eth_getBlockByNumber", "params": ["0x5", false], "id": 1});
+        assert_eq!(requested_block(&block_number, &[]), None);
+        // Without the block parameter the node reads the latest state
+        let no_block = json!({"jsonrpc": "2.0", "method": "eth_call", "params": [{}], "id": 1});
+        assert_eq!(requested_block(&no_block, &[]), None);
+    }
+
+    #[test]
+    fn requested_block_resolves_recent_hashes() {
+        let hash = format!("0x{:064x}", 7);
+        let mut rpc = rpc_with_head(1, 100, HistoryDepth::Unknown);
+        rpc.recent_blocks.push_back(BlockHeader { number: 99, timestamp: 0, hash: hash.clone(), parent_hash: String::new() });
+        let rpc_list = vec![rpc];
+
+        assert_eq!(requested_block(&state_read(json!({"blockHash": hash})), &rpc_list), Some(RequestedBlock::Number(99)));
+        assert_eq!(requested_block(&state_read(json!(hash)), &rpc_list), Some(RequestedBlock::Number(99)));
+        let old_hash = format!("0x{:064x}", 8);
+        assert_eq!(requested_block(&state_read(json!({"blockHash": old_hash})), &rpc_list), Some(RequestedBlock::UnknownHash));
+    }
+
+    #[test]
+    fn history_rank_only_excludes_known_depths() {
+        let depth = Some(StateDepth::Blocks(1000));
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Archive), depth), HistoryRank::Keeps);
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Pruned), depth), HistoryRank::Unverified);
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Unknown), depth), HistoryRank::Unverified);
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Blocks(500)), depth), HistoryRank::Missing);
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Pruned), Some(StateDepth::Blocks(100))), HistoryRank::Keeps);
+        // An unknown hash prefers the archive nodes without excluding any
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Blocks(500)), Some(StateDepth::Unknown)), HistoryRank::Unverified);
+        assert_eq!(history_rank(&rpc_with_head(1, 0, HistoryDepth::Archive), Some(StateDepth::Unknown)), HistoryRank::Keeps);
+    }
+
+    #[test]
+    fn record_missing_state_learns_from_the_head_of_the_node() {
+        let rpc_list = Arc::new(Mutex::new(vec![rpc_with_head(1, 1000, HistoryDepth::Pruned)]));
+        record_missing_state(&rpc_list, 1, Some(RequestedBlock::Number(700)));
+        let rpc = rpc_list.lock().unwrap()[0].clone();
+        assert_eq!(rpc.learned_history.map(|learned_history| learned_history.blocks), Some(299));
+        assert_eq!(history_of(&rpc), HistoryDepth::Blocks(299));
+        assert_eq!(rpc.history_depth, HistoryDepth::Pruned);
+    }
+
+    #[test]
+    fn record_missing_state_ignores_shallow_and_unknown_blocks() {
+        let rpc_list = Arc::new(Mutex::new(vec![rpc_with_head(1, 1000, HistoryDepth::Unknown)]));
+        // A missing state close to the head is taken as transient
+        record_missing_state(&rpc_list, 1, Some(RequestedBlock::Number(999)));
+        record_missing_state(&rpc_list, 1, Some(RequestedBlock::Number(1000 - MIN_LEARNED_HISTORY_DEPTH)));
+        record_missing_state(&rpc_list, 1, Some(RequestedBlock::UnknownHash));
+        record_missing_state(&rpc_list, 1, None);
+        assert_eq!(rpc_list.lock().unwrap()[0].learned_history, None);
+    }
+
+    #[test]
+    fn record_missing_state_keeps_archive_nodes() {
+        let rpc_list = Arc::new(Mutex::new(vec![rpc_with_head(1, 1000, HistoryDepth::Archive)]));
+        record_missing_state(&rpc_list, 1, Some(RequestedBlock::Number(1)));
+        assert_eq!(history_of(&rpc_list.lock().unwrap()[0]), HistoryDepth::Archive);
+    }
+
+    #[test]
+    fn learned_history_expires() {
+        let mut rpc = rpc_with_head(1, 1000, HistoryDepth::Blocks(900));
+        rpc.learned_history = Some(LearnedHistory { blocks: 200, expires_at: 0 });
+        assert_eq!(history_of(&rpc), HistoryDepth::Blocks(900));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+// Blocks of state a node without a configured or probed history is assumed to keep, the default of pruned geth nodes
+pub const DEFAULT_PRUNED_HISTORY_DEPTH: u64 = 128;
+// Block whose state only archive nodes still have, read by the history probe
+pub const ARCHIVE_PROBE_BLOCK: &str = "0x1";
+// A missing state at most this many blocks behind the head of the node is taken as transient and not learned
+pub const MIN_LEARNED_HISTORY_DEPTH: u64 = DEFAULT_PRUNED_HISTORY_DEPTH;
+// Time a history learned from a missing state error overrides the configured or probed one
+pub const LEARNED_HISTORY_TTL_MS: u64 = 600000;
+
+// State reading methods and the position of their block parameter
+pub const STATE_METHODS: [(&str, usize); 8] = [
+    ("eth_getBalance", 1),
+    ("eth_getCode", 1),
+    ("eth_getTransactionCount", 1),
+    ("eth_getStorageAt", 2),
+    ("eth_getProof", 2),
+    ("eth_call", 1),
+    ("eth_estimateGas", 1),
+    ("eth_createAccessList", 1),
+];
+
+// Fragments of the error messages of nodes that no longer have the state of a block, matched in lowercase
+pub const MISSING_STATE_ERROR_MESSAGES: [&str; 4] = [
+    "missing trie node",
+    "historical state",
+    "state not available",
+    "state is not available",
+];
+
+// How far back a node keeps the state of the chain
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum HistoryDepth {
+    #[default]
+    Unknown,     // neither configured nor probed, assumed to keep DEFAULT_PRUNED_HISTORY_DEPTH blocks
+    Pruned,      // probed as not archive, keeps at least DEFAULT_PRUNED_HISTORY_DEPTH blocks
+    Archive,     // keeps the state of every block
+    Blocks(u64), // keeps the state of the last n blocks, configured or learned
+}
+
+impl HistoryDepth {
+    // Whether the node keeps the state of the block `depth` blocks behind the chain head, None when unknown
+    pub fn keeps(&self, depth: u64) -> Option<bool> {
+        match self {
+            HistoryDepth::Unknown => None,
+            HistoryDepth::Pruned if depth <= DEFAULT_PRUNED_HISTORY_DEPTH => Some(true),
+            HistoryDepth::Pruned => None,
+            HistoryDepth::Archive => Some(true),
+            HistoryDepth::Blocks(blocks) => Some(depth <= *blocks),
+        }
+    }
+}
+
+impl fmt::Display for HistoryDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryDepth::Unknown => write!(f, "unknown"),
+            HistoryDepth::Pruned => write!(f, "pruned"),
+            HistoryDepth::Archive => write!(f, "archive"),
+            HistoryDepth::Blocks(blocks) => write!(f, "{}", blocks),
+        }
+    }
+}
+
+// History learned from a missing state error of the node, it expires so a transient error does not stick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LearnedHistory {
+    pub blocks: u64,     // blocks the node keeps at most
+    pub expires_at: u64, // timestamp in ms
+}
+
+// Block a state read is served at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestedBlock {
+    Number(u64),
+    UnknownHash, // a block hash missing from the recent blocks of the rpcs
+}
+
+impl RequestedBlock {
+    pub fn number(self) -> Option<u64> {
+        match self {
+            RequestedBlock::Number(number) => Some(number),
+            RequestedBlock::UnknownHash => None,
+        }
+    }
+}
+
+// How far behind the chain head a state read goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateDepth {
+    Blocks(u64),
+    Unknown, // an old block hash, the archive nodes are preferred and no node is excluded
+}
+
+// Where a node stands for a block, the nodes known to keep it are tried first and the ones known not to are skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HistoryRank {
+    Keeps,
+    Unverified,
+    Missing,
+}
@@ -0,0 +1,307 @@
+use crate::{
+    archive::{
+        functions::requested_block,
+        types::{
+            RequestedBlock,
+            STATE_METHODS,
+        },
+    },
+    cache::types::{
+        CacheEntry,
+        CachePolicy,
+        CacheSettings,
+        CacheStats,
+        FINALITY_DEPTH,
+        MOVING_BLOCK_TAGS,
+        RESPONSE_CACHE,
+    },
+    coalesce::functions::forward_coalesced_rpc_request,
+    config::types::Settings,
+    metrics::functions::record_cache_lookup,
+    routing::types::RpcTags,
+    rpc::{
+        functions::served_by_response,
+        types::Rpc,
+    },
+};
+
+use hyper::Response;
+use log::{debug, info};
+use serde_json::Value;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+pub async fn forward_cached_rpc_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize,
+                                        json_value: Value, rpc_tags: &RpcTags, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    let policy = cache_policy(&config.read().await.cache, &json_value);
+    let policy = match policy {
+        Some(policy) if chain_id != 0 => policy,
+        _ => return forward_coalesced_rpc_request(rpc_list, chain_id, json_value, rpc_tags, config).await,
+    };
+
+    let key = cache_key(chain_id, &json_value, rpc_tags);
+    let method = json_value["method"].as_str().unwrap_or_default().to_string();
+    // A response is only stored for the head it was requested at
+    let lookup_head = cached_chain_head(chain_id);
+    let request_block = requested_block(&json_value, &[]).and_then(RequestedBlock::number);
+    if let Some(response_string) = get_cached_response(&key, &json_value["id"]) {
+        debug!("Cache hit: {}", key);
+        record_cache_lookup(chain_id, &method, "hit");
+        return Ok(served_by_response("cache", response_string));
+    }
+    record_cache_lookup(chain_id, &method, "miss");
+
+    let response = forward_coalesced_rpc_request(rpc_list, chain_id, json_value, rpc_tags, config).await?;
+    if response.status().is_success() {
+        store_response(chain_id, key, policy, response.body(), request_block, lookup_head);
+    }
+    Ok(response)
+}
+
+pub fn cache_policy(cache_settings: &CacheSettings, json_value: &Value) -> Option<CachePolicy> {
+    // Only single requests are cached, batches are always forwarded
+    let method = json_value.get("method")?.as_str()?;
+    let policy = cache_settings.methods.get(method)?.clone();
+
+    // A block tag that moves with the head turns an immutable method into a per head one
+    let params = json_value.get("params").map(|params| params.to_string()).unwrap_or_default();
+    let moving_block = MOVING_BLOCK_TAGS.iter().any(|tag| params.contains(&format!("\"{}\"", tag)));
+    // Nodes read the state at latest when the block parameter is omitted
+    let omitted_block = STATE_METHODS
+        .iter()
+        .find(|(state_method, _)| *state_method == method)
+        .is_some_and(|(_, position)| json_value.get("params").and_then(|params| params.get(*position)).is_none_or(Value::is_null));
+    match policy {
+        CachePolicy::Forever if moving_block || omitted_block => Some(CachePolicy::UntilNextHead),
+        policy => Some(policy),
+    }
+}
+
+pub fn cache_key(chain_id: usize, json_value: &Value, rpc_tags: &RpcTags) -> String {
+    // Requests with other tags may be routed to other rpcs, the order the tags are given in does not matter
+    let mut rpc_tags = rpc_tags.clone();
+    rpc_tags.required.sort();
+    rpc_tags.required.dedup();
+    rpc_tags.preferred.sort();
+    rpc_tags.preferred.dedup();
+    // The request id is not part of the key, every client gets its own id back
+    format!("{}:{}:{}:{}",
+        chain_id,
+        json_value["method"].as_str().unwrap_or_default(),
+        json_value.get("params").map(|params| params.to_string()).unwrap_or_default(),
+        rpc_tags,
+    )
+}
+
+pub fn get_cached_response(key: &str, id: &Value) -> Option<String> {
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+
+    let expired = match cache.entries.get(key) {
+        Some(entry) => match entry.policy {
+            CachePolicy::Ttl(ttl_ms) => now.saturating_sub(entry.stored_at) > ttl_ms,
+            _ => false,
+        },
+        None => {
+            cache.misses += 1;
+            return None;
+        },
+    };
+    if expired {
+        cache.entries.pop(key);
+        cache.misses += 1;
+        return None;
+    }
+
+    cache.hits += 1;
+    let mut response = cache.entries.get(key)?.response.clone();
+    response["id"] = id.clone();
+    Some(response.to_string())
+}
+
+pub fn store_response(chain_id: usize, key: String, policy: CachePolicy, response_string: &str,
+                      request_block: Option<u64>, lookup_head: u64) {
+    // Only results are cached, errors and null results (i.e. an unknown receipt) may change
+    let response = match serde_json::from_str::<Value>(response_string) {
+        Ok(response) if response.get("error").is_none_or(|error| error.is_null())
+            && response.get("result").is_some_and(|result| !result.is_null()) => response,
+        _ => return,
+    };
+
+    // Responses about blocks a reorg can still replace only live until the next head
+    let policy = match policy {
+        CachePolicy::Forever if !is_final(&response["result"], request_block, lookup_head) => CachePolicy::UntilNextHead,
+        policy => policy,
+    };
+
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    // The head moved while the request was in flight, its invalidation already ran
+    if policy == CachePolicy::UntilNextHead && cache.chain_heads.get(&chain_id).copied().unwrap_or_default() != lookup_head {
+        debug!("Not caching {}, the head moved since the lookup", key);
+        return;
+    }
+    if policy == CachePolicy::UntilNextHead {
+        cache.head_keys.entry(chain_id).or_default().insert(key.clone());
+    }
+    let entry = CacheEntry {
+        chain_id,
+        policy,
+        response,
+        stored_at: chrono::Utc::now().timestamp_millis() as u64,
+    };
+    if let Some((evicted_key, evicted_entry)) = cache.entries.push(key.clone(), entry) {
+        if evicted_key != key {
+            cache.evictions += 1;
+            if let Some(head_keys) = cache.head_keys.get_mut(&evicted_entry.chain_id) {
+                head_keys.remove(&evicted_key);
+            }
+        }
+    }
+}
+
+pub fn cached_chain_head(chain_id: usize) -> u64 {
+    RESPONSE_CACHE.lock().unwrap().chain_heads.get(&chain_id).copied().unwrap_or_default()
+}
+
+pub fn is_final(result: &Value, request_block: Option<u64>, chain_head: u64) -> bool {
+    // The block of a result is its number, or the block it was included in, the most recent one for a list
+    let entries = match result {
+        Value::Array(entries) => entries.iter().collect(),
+        result => vec![result],
+    };
+    let mut blocks = request_block.into_iter().collect::<Vec<u64>>();
+    for entry in entries {
+        let block = match entry.get("blockNumber").or_else(|| entry.get("number")) {
+            Some(block) => block,
+            None => continue,
+        };
+        // A pending transaction has a null block number
+        match block.as_str().and_then(|block| u64::from_str_radix(block.trim_start_matches("0x"), 16).ok()) {
+            Some(block) => blocks.push(block),
+            None => return false,
+        }
+    }
+    blocks.iter().all(|block| block + FINALITY_DEPTH <= chain_head)
+}
+
+pub fn on_new_head(chain_id: usize, block_number: u64) {
+    // Every rpc of the chain reports the same heads, only the first report of a block invalidates
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    let chain_head = cache.chain_heads.entry(chain_id).or_default();
+    if block_number <= *chain_head {
+        return;
+    }
+    *chain_head = block_number;
+    drop(cache);
+    invalidate_head_entries(chain_id);
+}
+
+pub fn invalidate_head_entries(chain_id: usize) {
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    let head_keys = cache.head_keys.remove(&chain_id).unwrap_or_default();
+    for key in head_keys {
+        if cache.entries.pop(&key).is_some() {
+            cache.invalidations += 1;
+        }
+    }
+}
+
+pub fn configure_cache(cache_settings: &CacheSettings) {
+    let mut cache = RESPONSE_CACHE.lock().unwrap();
+    if cache.entries.cap().get() != cache_settings.max_entries {
+        info!("Response cache size set to {} entries", cache_settings.max_entries);
+        cache.entries.resize(NonZeroUsize::new(cache_settings.max_entries.max(1)).unwrap());
+    }
+}
+
+pub fn get_cache_stats() -> Result<Response<String>, hyper::Error> {
+    let cache = RESPONSE_CACHE.lock().unwrap();
+    let lookups = cache.hits + cache.misses;
+    let cache_stats = CacheStats {
+        entries: cache.entries.len(),
+        max_entries: cache.entries.cap().get(),
+        hits: cache.hits,
+        misses: cache.misses,
+        hit_rate: if lookups == 0 { 0.0 } else { cache.hits as f64 / lookups as f64 },
+        evictions: cache.evictions,
+        invalidations: cache.invalidations,
+    };
+    Ok(Response::new(serde_json::to_string(&cache_stats).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn is_final_uses_the_block_of_the_result() {
+        let receipt = json!({"blockNumber": "0x64", "status": "0x1"});
+        assert!(is_final(&receipt, None, 100 + FINALITY_DEPTH));
+        assert!(!is_final(&receipt, None, 99 + FINALITY_DEPTH));
+        assert!(!is_final(&json!({"number": "0x64"}), None, 100));
+        // A pending transaction is never final
+        assert!(!is_final(&json!({"blockNumber": null}), None, 1000));
+        // Results without a block are immutable
+        assert!(is_final(&json!("0xa"), None, 0));
+    }
+
+    #[test]
+    fn is_final_uses_the_most_recent_block() {
+        let logs = json!([{"blockNumber": "0x1"}, {"blockNumber": "0x64"}]);
+        assert!(!is_final(&logs, None, 99 + FINALITY_DEPTH));
+        assert!(is_final(&logs, None, 100 + FINALITY_DEPTH));
+        assert!(!is_final(&json!("0x0"), Some(100), 99 + FINALITY_DEPTH));
+    }
+
+    #[test]
+    fn cache_policy_reads_an_omitted_block_as_latest() {
+        let cache_settings = CacheSettings {
+            methods: [("eth_getBalance".to_string(), CachePolicy::Forever)].into_iter().collect(),
+            ..CacheSettings::default()
+        };
+        let policy = |params: Value| cache_policy(&cache_settings, &json!({"method": "eth_getBalance", "params": params}));
+        assert_eq!(policy(json!(["0xabc", "0x10"])), Some(CachePolicy::Forever));
+        assert_eq!(policy(json!(["0xabc", "latest"])), Some(CachePolicy::UntilNextHead));
+        assert_eq!(policy(json!(["0xabc"])), Some(CachePolicy::UntilNextHead));
+        assert_eq!(policy(json!(["0xabc", null])), Some(CachePolicy::UntilNextHead));
+    }
+
+    #[test]
+    fn cache_key_sorts_the_rpc_tags() {
+        let request = json!({"method": "eth_chainId", "params": [], "id": 1});
+        let key = cache_key(1, &request, &RpcTags::parse("trace,archive,~paid"));
+        assert_eq!(key, cache_key(1, &request, &RpcTags::parse("~paid,archive,trace")));
+        assert_ne!(key, cache_key(1, &request, &RpcTags::default()));
+        assert_ne!(key, cache_key(1, &request, &RpcTags::parse("trace,archive,paid")));
+    }
+
+    #[test]
+    fn store_response_skips_responses_outdated_by_a_new_head() {
+        let chain_id = 991;
+        on_new_head(chain_id, 10);
+        let lookup_head = cached_chain_head(chain_id);
+        on_new_head(chain_id, 11);
+        store_response(chain_id, "991:eth_call:latest".to_string(), CachePolicy::UntilNextHead,
+                       r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#, None, lookup_head);
+        assert_eq!(get_cached_response("991:eth_call:latest", &json!(1)), None);
+
+        store_response(chain_id, "991:eth_call:latest".to_string(), CachePolicy::UntilNextHead,
+                       r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#, None, cached_chain_head(chain_id));
+        assert!(get_cached_response("991:eth_call:latest", &json!(1)).is_some());
+    }
+
+    #[test]
+    fn store_response_invalidates_recent_blocks_on_the_next_head() {
+        let chain_id = 992;
+        on_new_head(chain_id, 1000);
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":{"blockNumber":"0x3e8"}}"#;
+        store_response(chain_id, "992:eth_getTransactionReceipt:recent".to_string(), CachePolicy::Forever,
+                       response, None, cached_chain_head(chain_id));
+        assert!(get_cached_response("992:eth_getTransactionReceipt:recent", &json!(1)).is_some());
+        on_new_head(chain_id, 1001);
+        assert_eq!(get_cached_response("992:eth_getTransactionReceipt:recent", &json!(1)), None);
+    }
+}
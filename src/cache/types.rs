@@ -0,0 +1,99 @@
+use crate::head::types::BLOCK_HISTORY_SIZE;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// Maximum number of cached responses when [cache] has no max_entries
+pub const DEFAULT_CACHE_MAX_ENTRIES: usize = 10000;
+// Block tags whose block changes with every head, a "forever" response for them only lives until the next head
+pub const MOVING_BLOCK_TAGS: [&str; 4] = ["latest", "pending", "safe", "finalized"];
+// Blocks behind the chain head before a "forever" response about a block is cached forever, as deep as the
+// reorgs the head tracking detects, the responses about more recent blocks only live until the next head
+pub const FINALITY_DEPTH: u64 = BLOCK_HISTORY_SIZE as u64;
+
+lazy_static! {
+    // Shared by the request path and the head tracking that invalidates it
+    pub static ref RESPONSE_CACHE: Mutex<ResponseCache> = Mutex::new(ResponseCache::new(DEFAULT_CACHE_MAX_ENTRIES));
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CachePolicy {
+    Forever,       // immutable data, i.e. eth_chainId or eth_getBlockByHash
+    UntilNextHead, // valid until the chain head moves, i.e. eth_call at "latest"
+    Ttl(u64),      // valid for the given number of ms
+}
+
+impl FromStr for CachePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "forever" => Ok(CachePolicy::Forever),
+            "head" => Ok(CachePolicy::UntilNextHead),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheSettings {
+    pub max_entries: usize,
+    pub methods: HashMap<String, CachePolicy>, // methods without a policy are never cached
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub chain_id: usize,
+    pub policy: CachePolicy,
+    pub response: Value, // the upstream response, its id is replaced by the id of every request it serves
+    pub stored_at: u64,  // timestamp in ms
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub max_entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub evictions: u64,     // entries dropped to make room for new ones
+    pub invalidations: u64, // entries dropped because the chain head moved
+}
+
+pub struct ResponseCache {
+    pub entries: LruCache<String, CacheEntry>,
+    pub head_keys: HashMap<usize, HashSet<String>>, // keys of the UntilNextHead entries of each chain
+    pub chain_heads: HashMap<usize, u64>,           // highest block seen per chain
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(max_entries.max(1)).unwrap()),
+            head_keys: HashMap::new(),
+            chain_heads: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            invalidations: 0,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{
+    cache::functions::cache_key,
+    coalesce::types::{
+        InFlightGuard,
+        COALESCED_METHODS,
+        IN_FLIGHT,
+    },
+    config::types::Settings,
+    metrics::functions::{
+        record_coalesced_request,
+        request_method,
+    },
+    routing::types::RpcTags,
+    rpc::{
+        functions::forward_rpc_request,
+        types::Rpc,
+    },
+};
+
+use hyper::Response;
+use log::debug;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, RwLock};
+
+pub async fn forward_coalesced_rpc_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize,
+                                           json_value: Value, rpc_tags: &RpcTags, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    if !config.read().await.coalesce_requests || !COALESCED_METHODS.contains(&request_method(&json_value).as_str()) {
+        return forward_rpc_request(rpc_list, chain_id, json_value, rpc_tags, config).await;
+    }
+
+    // The first request of a key forwards it, the identical ones arriving meanwhile wait for its response
+    let key = cache_key(chain_id, &json_value, rpc_tags);
+    let waiter = match IN_FLIGHT.lock().unwrap().entry(key.clone()) {
+        Entry::Occupied(mut entry) => {
+            let (sender, receiver) = oneshot::channel();
+            entry.get_mut().push(sender);
+            Some(receiver)
+        },
+        Entry::Vacant(entry) => {
+            entry.insert(Vec::new());
+            None
+        },
+    };
+
+    match waiter {
+        Some(receiver) => match receiver.await {
+            Ok(shared_response) => {
+                debug!("Coalesced request: {}", key);
+                record_coalesced_request(chain_id, &request_method(&json_value));
+                let mut response = Response::new(with_request_id(&shared_response.body, &json_value["id"]));
+                *response.status_mut() = shared_response.status;
+                *response.headers_mut() = shared_response.headers;
+                Ok(response)
+            },
+            // The forwarding request was dropped before answering
+            Err(_) => forward_rpc_request(rpc_list, chain_id, json_value, rpc_tags, config).await,
+        },
+        None => {
+            let guard = InFlightGuard { key };
+            let response = forward_rpc_request(rpc_list, chain_id, json_value, rpc_tags, config).await?;
+            guard.finish(&response);
+            Ok(response)
+        },
+    }
+}
+
+pub fn with_request_id(response_string: &str, id: &Value) -> String {
+    // Give the shared response the id of the client it is sent to
+    match serde_json::from_str::<Value>(response_string) {
+        Ok(mut response) if response.is_object() => {
+            response["id"] = id.clone();
+            response.to_string()
+        },
+        _ => response_string.to_string(),
+    }
+}
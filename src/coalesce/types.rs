@@ -0,0 +1,80 @@
+use hyper::header::HeaderMap;
+use hyper::{Response, StatusCode};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+lazy_static! {
+    // Requests being forwarded, by chain, method and params, with the clients waiting for their response
+    pub static ref IN_FLIGHT: Mutex<HashMap<String, Vec<oneshot::Sender<SharedResponse>>>> = Mutex::new(HashMap::new());
+}
+
+// Read-only methods whose identical calls can share a response, filter, subscription and send methods
+// create or change state on the node and are always forwarded
+pub const COALESCED_METHODS: [&str; 30] = [
+    "eth_blockNumber",
+    "eth_chainId",
+    "net_version",
+    "eth_syncing",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_blobBaseFee",
+    "eth_feeHistory",
+    "eth_getBalance",
+    "eth_getCode",
+    "eth_getTransactionCount",
+    "eth_getStorageAt",
+    "eth_getProof",
+    "eth_call",
+    "eth_estimateGas",
+    "eth_createAccessList",
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getBlockReceipts",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getTransactionByHash",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionReceipt",
+    "eth_getLogs",
+    "debug_traceTransaction",
+    "debug_traceCall",
+    "trace_transaction",
+    "trace_block",
+];
+
+// Copy of the response of the forwarding request, sent to every waiter
+#[derive(Debug, Clone)]
+pub struct SharedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+// Held by the request forwarding a coalesced call, its waiters are released even if the forwarding is dropped
+pub struct InFlightGuard {
+    pub key: String,
+}
+
+impl InFlightGuard {
+    pub fn finish(self, response: &Response<String>) {
+        let waiters = IN_FLIGHT.lock().unwrap().remove(&self.key).unwrap_or_default();
+        let shared_response = SharedResponse {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: response.body().clone(),
+        };
+        for waiter in waiters {
+            let _ = waiter.send(shared_response.clone());
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        // Without a response the waiters forward their request themselves
+        IN_FLIGHT.lock().unwrap().remove(&self.key);
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub table: String,
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    pub fn new(table: &str, key: &str, message: String) -> Self {
+        Self {
+            table: table.to_string(),
+            key: key.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "[{}]: {}", self.table, self.message)
+        } else {
+            write!(f, "[{}] {}: {}", self.table, self.key, self.message)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error), // config path and the error reading it
+    Toml(String),               // the file is not valid TOML
+    Invalid(Vec<ConfigIssue>),  // every problem found in the tables
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "Error opening config file at {}: {}", path, error),
+            ConfigError::Toml(message) => write!(f, "Error parsing TOML: {}", message),
+            ConfigError::Invalid(issues) => {
+                write!(f, "Invalid config, {} problem(s) found:", issues.len())?;
+                for issue in issues {
+                    write!(f, "\n  {}", issue)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
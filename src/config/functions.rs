@@ -0,0 +1,399 @@
+use crate::{
+    archive::types::HistoryDepth,
+    cache::functions::configure_cache,
+    config::{
+        errors::ConfigError,
+        types::{
+            Settings,
+            RESERVED_TABLES,
+        },
+    },
+    metrics::functions::{
+        remove_upstream_metrics,
+        upstream_label,
+    },
+    rpc::{
+        functions::rpc_host,
+        types::{
+            AddRpcRequest,
+            Rpc,
+        },
+    },
+    head::functions::start_head_tracking,
+};
+
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn, LevelFilter};
+use std::fs;
+use std::io::{Error, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::RwLock;
+use toml_edit::{value, Array, Document, Item, Table};
+
+// How often the config file modification time is checked when watch_config is enabled
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // Serializes the read-modify-write cycles on the config file
+    static ref PERSIST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+pub async fn persist_rpc(config_path: &str, add_rpc_request: &AddRpcRequest) -> Result<(), Error> {
+    let (config_file, add_rpc_request) = (config_path.to_string(), add_rpc_request.clone());
+    edit_config_file(config_path, move |document| {
+        let config_path = config_file.as_str();
+        if find_rpc_table_name(document, &add_rpc_request.url).is_some() {
+            debug!("Rpc already persisted in {}: {}", config_path, add_rpc_request.url);
+            return false;
+        }
+
+        let table_name = unique_table_name(document, &add_rpc_request.url);
+        document.insert(&table_name, Item::Table(rpc_table(&add_rpc_request)));
+        info!("Rpc persisted as [{}] in {}: {}", table_name, config_path, add_rpc_request.url);
+        true
+    }).await
+}
+
+pub async fn persist_rpc_removal(config_path: &str, url: &str) -> Result<(), Error> {
+    let (config_file, url) = (config_path.to_string(), url.to_string());
+    edit_config_file(config_path, move |document| {
+        let (config_path, url) = (config_file.as_str(), url.as_str());
+        match find_rpc_table_name(document, url) {
+            Some(table_name) => {
+                document.remove(&table_name);
+                info!("Rpc [{}] removed from {}: {}", table_name, config_path, url);
+                true
+            },
+            None => false,
+        }
+    }).await
+}
+
+pub async fn persist_rpc_update(config_path: &str, rpc: &Rpc) -> Result<(), Error> {
+    let (config_file, rpc) = (config_path.to_string(), rpc.clone());
+    edit_config_file(config_path, move |document| {
+        let config_path = config_file.as_str();
+        let table_name = match find_rpc_table_name(document, &rpc.url) {
+            Some(table_name) => table_name,
+            None => return false,
+        };
+        // Only the keys that can be changed at runtime are touched
+        let table = &mut document[&table_name];
+        table["chain_id"] = value(rpc.chain_id as i64);
+        table["rpc_location"] = value(format!("{:?}", rpc.rpc_location));
+        table["enabled"] = value(rpc.enabled);
+        match rpc.tags.is_empty() {
+            true => {
+                if let Some(table) = table.as_table_like_mut() {
+                    table.remove("tags");
+                }
+            },
+            false => table["tags"] = value(rpc.tags.iter().collect::<Array>()),
+        }
+        info!("Rpc [{}] updated in {}: {}", table_name, config_path, rpc.url);
+        true
+    }).await
+}
+
+pub async fn edit_config_file<F>(config_path: &str, edit: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Document) -> bool + Send + 'static,
+{
+    // The file io and the lock wait run on the blocking pool instead of the runtime workers
+    let config_path = config_path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let _persist_guard = PERSIST_LOCK.lock().unwrap();
+
+        let conf_file = fs::read_to_string(&config_path)?;
+        // toml_edit keeps the comments, formatting and ordering of the existing tables
+        let mut document = conf_file
+            .parse::<Document>()
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+
+        // The file is only rewritten when the edit changed something
+        if !edit(&mut document) {
+            return Ok(());
+        }
+        write_atomically(&config_path, &conf_file, &document.to_string())
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+pub fn find_rpc_table_name(document: &Document, url: &str) -> Option<String> {
+    document
+        .iter()
+        .filter(|(table_name, _)| !RESERVED_TABLES.contains(table_name))
+        .find(|(_, item)| item.get("url").and_then(|u| u.as_str()) == Some(url))
+        .map(|(table_name, _)| table_name.to_string())
+}
+
+pub fn rpc_table(add_rpc_request: &AddRpcRequest) -> Table {
+    // Same schema Settings::create_from_file reads
+    let mut table = Table::new();
+    table.insert("url", value(add_rpc_request.url.as_str()));
+    if let Some(ws_url) = &add_rpc_request.ws_url {
+        table.insert("ws_url", value(ws_url.as_str()));
+    }
+    table.insert("chain_id", value(add_rpc_request.chain_id as i64));
+    table.insert("rpc_location", value(add_rpc_request.rpc_location.as_str()));
+    if let Some(head_source) = &add_rpc_request.head_source {
+        table.insert("head_source", value(head_source.as_str()));
+    }
+    if let Some(max_batch_size) = add_rpc_request.max_batch_size {
+        table.insert("max_batch_size", value(max_batch_size as i64));
+    }
+    if !add_rpc_request.tags.is_empty() {
+        table.insert("tags", value(add_rpc_request.tags.iter().collect::<Array>()));
+    }
+    table
+}
+
+pub fn unique_table_name(document: &Document, url: &str) -> String {
+    // Derive the table name from the rpc host, i.e. rpc-127-0-0-1-8545
+    let base_name: String = format!("rpc-{}", rpc_host(url))
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+
+    let mut table_name = base_name.clone();
+    let mut suffix = 2;
+    while document.contains_key(&table_name) {
+        table_name = format!("{}-{}", base_name, suffix);
+        suffix += 1;
+    }
+    table_name
+}
+
+pub fn write_atomically(config_path: &str, previous: &str, contents: &str) -> Result<(), Error> {
+    // The file holds the admin token, the backup and the replacement keep its permissions and are
+    // restricted before any byte is written
+    let permissions = fs::metadata(config_path)?.permissions();
+    // Keep a backup of the previous file and replace it with a rename so readers never see a partial file
+    let mut backup_file = fs::File::create(format!("{}.bak", config_path))?;
+    backup_file.set_permissions(permissions.clone())?;
+    backup_file.write_all(previous.as_bytes())?;
+    let tmp_path = format!("{}.tmp", config_path);
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+    tmp_file.set_permissions(permissions)?;
+    tmp_file.write_all(contents.as_bytes())?;
+    // On disk before the rename, so a crash leaves the previous or the new file and never an empty one
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, config_path)?;
+    // The rename itself is durable once the directory is synced
+    let config_dir = match Path::new(config_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(config_dir)?.sync_all()
+}
+
+pub fn apply_log_level(log_level: LevelFilter) {
+    // The logger is built with every level enabled, the max level does the filtering
+    log::set_max_level(log_level);
+}
+
+pub async fn watch_config(rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>) {
+    let config_path = config.read().await.config_path.clone();
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    let mut last_modified = config_modified(&config_path);
+
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                info!("SIGHUP received, reloading {}", config_path);
+            },
+            _ = interval.tick() => {
+                if !config.read().await.watch_config {
+                    continue;
+                }
+                let modified = config_modified(&config_path);
+                if modified == last_modified {
+                    continue;
+                }
+                info!("{} changed on disk, reloading", config_path);
+            },
+        }
+
+        last_modified = config_modified(&config_path);
+        if let Err(e) = reload_config(&rpc_list, &config).await {
+            error!("Error reloading {}, keeping the current config: {}", config_path, e);
+        }
+    }
+}
+
+fn config_modified(config_path: &str) -> Option<SystemTime> {
+    fs::metadata(config_path).and_then(|metadata| metadata.modified()).ok()
+}
+
+pub async fn reload_config(rpc_list: &Arc<Mutex<Vec<Rpc>>>, config: &Arc<RwLock<Settings>>) -> Result<(), ConfigError> {
+    let config_path = config.read().await.config_path.clone();
+    let conf_file = fs::read_to_string(&config_path).map_err(|e| ConfigError::Io(config_path.clone(), e))?;
+
+    let mut new_settings = Settings::create_from_file(conf_file).await?;
+    new_settings.config_path = config_path;
+
+    apply_rpc_list(rpc_list, new_settings.rpc_list.clone(), config);
+    apply_settings(config, new_settings).await;
+    Ok(())
+}
+
+pub fn apply_rpc_list(rpc_list: &Arc<Mutex<Vec<Rpc>>>, new_rpc_list: Vec<Rpc>, config: &Arc<RwLock<Settings>>) {
+    // Rpcs are matched by url, unchanged rpcs keep their id and latency history
+    let new_rpc_urls: Vec<String> = new_rpc_list.iter().map(|rpc| rpc.url.clone()).collect();
+    let (removed_rpcs, added_rpcs) = {
+        let mut rpc_guard = rpc_list.lock().unwrap();
+
+        // A changed ws_url or head_source needs new head tracking, so the rpc is replaced.
+        // Rpcs added through the admin api without persist are not in the file and are kept
+        let is_kept = |rpc: &Rpc| match new_rpc_list.iter().find(|new_rpc| new_rpc.url == rpc.url) {
+            Some(new_rpc) => new_rpc.ws_url == rpc.ws_url && new_rpc.head_source == rpc.head_source,
+            None => rpc.admin_override,
+        };
+        let removed_rpcs: Vec<Rpc> = rpc_guard.iter().filter(|rpc| !is_kept(rpc)).cloned().collect();
+        rpc_guard.retain(is_kept);
+
+        let mut added_rpcs = Vec::new();
+        for new_rpc in new_rpc_list {
+            match rpc_guard.iter_mut().find(|rpc| rpc.url == new_rpc.url) {
+                // The admin api changes were not persisted, the file would undo them
+                Some(rpc) if rpc.admin_override => {
+                    warn!("Reload: keeping the admin api changes of rpc {} over the config file", rpc.url);
+                },
+                Some(rpc) => {
+                    if new_rpc.chain_id != rpc.chain_id {
+                        remove_upstream_metrics(rpc.chain_id, &upstream_label(rpc));
+                    }
+                    rpc.set_chain_id(new_rpc.chain_id);
+                    rpc.rpc_location = new_rpc.rpc_location;
+                    rpc.enabled = new_rpc.enabled;
+                    rpc.max_batch_size = new_rpc.max_batch_size;
+                    rpc.tags = new_rpc.tags;
+                    // A probed history is kept unless the config sets one
+                    if new_rpc.history_depth != HistoryDepth::Unknown {
+                        rpc.history_depth = new_rpc.history_depth;
+                    }
+                },
+                None => {
+                    added_rpcs.push(new_rpc.clone());
+                    rpc_guard.push(new_rpc);
+                },
+            }
+        }
+        (removed_rpcs, added_rpcs)
+    };
+
+    for rpc in rpc_list.lock().unwrap().iter().filter(|rpc| rpc.admin_override && !new_rpc_urls.contains(&rpc.url)) {
+        warn!("Reload: keeping rpc {} added through the admin api, it is not in the config file", rpc.url);
+    }
+    for removed_rpc in removed_rpcs {
+        info!("Reload: removing rpc {}", removed_rpc.url);
+        removed_rpc.ws_shutdown.notify_one();
+        remove_upstream_metrics(removed_rpc.chain_id, &upstream_label(&removed_rpc));
+    }
+    for added_rpc in added_rpcs {
+        info!("Reload: adding rpc {}", added_rpc.url);
+        tokio::task::spawn(start_head_tracking(rpc_list.clone(), added_rpc.id, config.clone()));
+    }
+}
+
+pub async fn apply_settings(config: &Arc<RwLock<Settings>>, new_settings: Settings) {
+    let mut config_guard = config.write().await;
+
+    // The listeners are bound once at startup
+    if config_guard.address != new_settings.address || config_guard.admin_address != new_settings.admin_address {
+        warn!("Reload: address and admin address changes require a restart");
+    }
+
+    apply_log_level(LevelFilter::from_str(&new_settings.log_level).unwrap());
+    config_guard.log_level = new_settings.log_level;
+    config_guard.stats_vec_size = new_settings.stats_vec_size;
+    config_guard.algo = new_settings.algo;
+    config_guard.broadcast_size = new_settings.broadcast_size;
+    config_guard.broadcast_selection = new_settings.broadcast_selection;
+    config_guard.ejection = new_settings.ejection;
+    config_guard.poll_interval_ms = new_settings.poll_interval_ms;
+    config_guard.ws_fallback_after_ms = new_settings.ws_fallback_after_ms;
+    config_guard.stale_head_factor = new_settings.stale_head_factor;
+    config_guard.coalesce_requests = new_settings.coalesce_requests;
+    config_guard.serve_locally = new_settings.serve_locally;
+    config_guard.max_request_size = new_settings.max_request_size;
+    config_guard.max_batch_size = new_settings.max_batch_size;
+    config_guard.chains = new_settings.chains;
+    configure_cache(&new_settings.cache);
+    config_guard.cache = new_settings.cache;
+    config_guard.routing_rules = new_settings.routing_rules;
+    config_guard.persist = new_settings.persist;
+    config_guard.watch_config = new_settings.watch_config;
+    config_guard.admin_token = new_settings.admin_token;
+    config_guard.admin_allowed_ips = new_settings.admin_allowed_ips;
+    config_guard.rpc_list = new_settings.rpc_list;
+    info!("Reload: config applied, algo: {:?}", config_guard.algo);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(id: usize, url: &str, tags: &[&str]) -> Rpc {
+        Rpc { id, url: url.to_string(), tags: tags.iter().map(|tag| tag.to_string()).collect(), ..Rpc::default() }
+    }
+
+    #[test]
+    fn write_atomically_keeps_the_permissions_of_the_config_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rpc_config_permissions_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("rpc_config.toml");
+        let config_path = config_path.to_str().unwrap();
+        fs::write(config_path, "previous").unwrap();
+        fs::set_permissions(config_path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomically(config_path, "previous", "next").unwrap();
+
+        let mode = |path: &str| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(fs::read_to_string(config_path).unwrap(), "next");
+        assert_eq!(mode(config_path), 0o600);
+        assert_eq!(mode(&format!("{}.bak", config_path)), 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_the_admin_api_changes_without_persist() {
+        let mut patched = rpc(1, "http://patched", &["archive"]);
+        patched.enabled = false;
+        patched.admin_override = true;
+        let mut added = rpc(2, "http://added", &[]);
+        added.admin_override = true;
+        let rpc_list = Arc::new(Mutex::new(vec![patched, added, rpc(3, "http://file", &[])]));
+        let config = Arc::new(RwLock::new(Settings::default()));
+
+        apply_rpc_list(&rpc_list, vec![rpc(4, "http://patched", &["paid"]), rpc(5, "http://file", &["paid"])], &config);
+
+        let rpc_guard = rpc_list.lock().unwrap();
+        let summary: Vec<(usize, bool, Vec<String>)> = rpc_guard.iter().map(|rpc| (rpc.id, rpc.enabled, rpc.tags.clone())).collect();
+        assert_eq!(summary, vec![
+            (1, false, vec!["archive".to_string()]),
+            (2, true, vec![]),
+            (3, true, vec!["paid".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn reload_removes_the_rpcs_missing_from_the_file() {
+        let rpc_list = Arc::new(Mutex::new(vec![rpc(1, "http://file", &[]), rpc(2, "http://gone", &[])]));
+        let config = Arc::new(RwLock::new(Settings::default()));
+
+        apply_rpc_list(&rpc_list, vec![rpc(3, "http://file", &[])], &config);
+
+        let ids: Vec<usize> = rpc_list.lock().unwrap().iter().map(|rpc| rpc.id).collect();
+        assert_eq!(ids, vec![1]);
+    }
+}
@@ -1 +1,3 @@
+pub mod errors;
 pub mod types;
+pub mod functions;
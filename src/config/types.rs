@@ -1,19 +1,56 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use clap::{
+    ArgMatches,
     Command,
     Arg,
 };
-use log::info;
+use log::{info, LevelFilter};
+use toml::value::{Table, Value};
 use crate::{
+    archive::types::HistoryDepth,
+    cache::types::{
+        CachePolicy,
+        CacheSettings,
+    },
+    config::errors::{
+        ConfigError,
+        ConfigIssue,
+    },
+    routing::{
+        functions::is_valid_tag,
+        types::RoutingRule,
+    },
+    head::types::{
+        DEFAULT_POLL_INTERVAL_MS,
+        DEFAULT_STALE_HEAD_FACTOR,
+        DEFAULT_WS_FALLBACK_AFTER_MS,
+    },
     rpc::types::{
+        EjectionPolicy,
+        HeadSource,
         Rpc,
         RpcLocation,
+        DEFAULT_MAX_BATCH_SIZE,
+    },
+    sort::types::{
+        Algo,
+        BlockLagPolicy,
     },
-    sort::types::Algo,
 };
 
+// Tables of the config file that are not RPCs
+pub const RESERVED_TABLES: [&str; 5] = ["proto_balancer", "admin", "chains", "cache", "routing_rule"];
+
+// Settings of a [chains.<chain_id>] table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainSettings {
+    pub block_time_ms: Option<u64>, // expected time between two blocks, enables the stale head watchdog
+    pub max_block_lag: Option<u64>, // blocks an rpc can be behind the chain head and still serve reads
+    pub block_lag_policy: BlockLagPolicy,
+}
 
 #[derive(Debug, Clone)]
 pub struct Settings {
@@ -22,6 +59,25 @@ pub struct Settings {
     pub log_level: String,
     pub stats_vec_size: usize,
     pub algo: Algo,
+    pub broadcast_size: usize,
+    pub broadcast_selection: Algo,
+    pub ejection: EjectionPolicy,
+    pub poll_interval_ms: u64,
+    pub ws_fallback_after_ms: u64,
+    pub stale_head_factor: u64,
+    pub coalesce_requests: bool,
+    pub serve_locally: bool,
+    pub max_request_size: usize,
+    pub max_batch_size: usize,
+    pub chains: BTreeMap<usize, ChainSettings>,
+    pub cache: CacheSettings,
+    pub routing_rules: Vec<RoutingRule>,
+    pub config_path: String,
+    pub persist: bool,
+    pub watch_config: bool,
+    pub admin_address: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub admin_allowed_ips: Vec<IpAddr>,
 }
 
 impl Default for Settings {
@@ -32,141 +88,536 @@ impl Default for Settings {
             log_level: String::from("info"),
             stats_vec_size: 1000,
             algo: Algo::MinLatency,
+            broadcast_size: 3,
+            broadcast_selection: Algo::MinLatency,
+            ejection: EjectionPolicy::default(),
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            ws_fallback_after_ms: DEFAULT_WS_FALLBACK_AFTER_MS,
+            stale_head_factor: DEFAULT_STALE_HEAD_FACTOR,
+            coalesce_requests: true,
+            serve_locally: false,
+            max_request_size: 10 * 1024 * 1024,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            chains: BTreeMap::new(),
+            cache: CacheSettings::default(),
+            routing_rules: Vec::new(),
+            config_path: String::from("rpc_config.toml"),
+            persist: false,
+            watch_config: false,
+            admin_address: None,
+            admin_token: None,
+            admin_allowed_ips: Vec::new(),
         }
     }
 }
 
 impl Settings {
-    pub async fn new(matches: Command) -> Settings {
-        let matches = matches.get_matches();
-
+    pub async fn new(matches: &ArgMatches) -> Result<Settings, ConfigError> {
         // Try to open the file at the path specified in the args
         let path = matches.get_one::<String>("config").unwrap();
-        let file: Option<String> = match fs::read_to_string(path) {
-            Ok(file) => Some(file),
-            Err(_) => panic!("\x1b[31mErr:\x1b[0m Error opening config file at {}", path),
-        };
+        let file = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
 
-        if let Some(file) = file {
-            info!("\x1b[35mInfo:\x1b[0m Using config file at {}", path);
-            return Settings::create_from_file(file).await;
-        }
-        else{
-            panic!("\x1b[31mErr:\x1b[0m rpc_config.toml file does not exist");
-        }
+        info!("Using config file at {}", path);
+        let mut settings = Settings::create_from_file(file).await?;
+        settings.config_path = path.clone();
+        Ok(settings)
     }
 
-    async fn create_from_file(conf_file: String) -> Settings {
-        let parsed_toml = conf_file.parse::<toml::value::Value>().expect("Error parsing TOML");
+    pub async fn create_from_file(conf_file: String) -> Result<Settings, ConfigError> {
+        let parsed_toml = conf_file
+            .parse::<Value>()
+            .map_err(|e| ConfigError::Toml(e.to_string()))?;
+        let root_table = parsed_toml
+            .as_table()
+            .ok_or_else(|| ConfigError::Toml("the config file must be a table".to_string()))?;
+
+        // Every problem is collected so they can all be reported at once
+        let mut issues: Vec<ConfigIssue> = Vec::new();
+        let defaults = Settings::default();
 
-        let table_names: Vec<&String> = parsed_toml.as_table().unwrap().keys().collect::<Vec<_>>();
+        let empty_table = Table::new();
+        let proto_balancer_table = match root_table.get("proto_balancer") {
+            Some(Value::Table(table)) => table,
+            Some(_) => {
+                issues.push(ConfigIssue::new("proto_balancer", "", "expected a table".to_string()));
+                &empty_table
+            },
+            None => {
+                issues.push(ConfigIssue::new("proto_balancer", "", "missing table".to_string()));
+                &empty_table
+            },
+        };
+        let mut reader = TableReader::new("proto_balancer", proto_balancer_table, &mut issues);
 
-        let proto_balancer_table = parsed_toml
-            .get("proto_balancer")
-            .expect("\x1b[31mErr:\x1b[0m Missing proto_balancer table!")
-            .as_table()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse proto_balancer table!");
-        let address = proto_balancer_table
-            .get("address")
-            .expect("\x1b[31mErr:\x1b[0m Missing address!")
-            .as_str()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse address as str!");
-
-        // Build the SocketAddr
-        let port = 3000;
-        // Replace `localhost` if it exists
-        let address = address.replace("localhost", "127.0.0.1");
-        // If the address contains `:` don't concatenate the port and just pass the address
-        let address = if address.contains(':') {
-            address.to_string()
-        } else {
-            format!("{}:{}", address, port)
+        let address = reader
+            .required_parsed("address", "an address (i.e. 127.0.0.1:3000)", parse_address)
+            .unwrap_or(defaults.address);
+
+        let log_level = reader
+            .required_parsed("log_level", "a log level (off, error, warn, info, debug, trace)", LevelFilter::from_str)
+            .map(|log_level| log_level.to_string().to_lowercase())
+            .unwrap_or(defaults.log_level);
+
+        let stats_vec_size = reader
+            .required_usize("stats_vec_size", 1)
+            .unwrap_or(defaults.stats_vec_size);
+
+        let algo = reader
+            .get_parsed("algo", "min_latency, round_robin or broadcast", Algo::from_str)
+            .unwrap_or(defaults.algo);
+
+        // Number of top ranked RPCs a request is raced against when algo = "broadcast"
+        let broadcast_size = reader
+            .get_usize("broadcast_size", 1)
+            .unwrap_or(defaults.broadcast_size);
+
+        // Algorithm used to rank the RPCs before picking the broadcast targets
+        let broadcast_selection = reader
+            .get_parsed("broadcast_selection", "min_latency or round_robin", |s| match Algo::from_str(s) {
+                Ok(Algo::Broadcast) | Err(_) => Err(()),
+                Ok(selection) => Ok(selection),
+            })
+            .unwrap_or(defaults.broadcast_selection);
+
+        // Passive outlier ejection, RPCs that keep failing are skipped for a cooldown
+        let ejection = EjectionPolicy {
+            failure_streak: reader
+                .get_usize("ejection_failure_streak", 1)
+                .and_then(|streak| u32::try_from(streak).ok())
+                .unwrap_or(defaults.ejection.failure_streak),
+            error_rate: reader
+                .get_f64("ejection_error_rate", 0.0, 1.0)
+                .unwrap_or(defaults.ejection.error_rate),
+            window: reader
+                .get_usize("ejection_window", 1)
+                .unwrap_or(defaults.ejection.window),
+            cooldown_ms: reader
+                .get_usize("ejection_cooldown_ms", 0)
+                .map(|cooldown_ms| cooldown_ms as u64)
+                .unwrap_or(defaults.ejection.cooldown_ms),
         };
-        let address = address
-            .parse::<SocketAddr>()
-            .expect("\x1b[31mErr:\x1b[0m Could not address to SocketAddr!");
-
-        let log_level = proto_balancer_table
-            .get("log_level")
-            .expect("\x1b[31mErr:\x1b[0m Missing log_level!")
-            .as_str()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse log_level as str!");
-
-        let stats_vec_size = proto_balancer_table
-            .get("stats_vec_size")
-            .expect("\x1b[31mErr:\x1b[0m Missing stats_vec_size!")
-            .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse stats_vec_size as integer!") as usize;
 
-        let algo = Algo::from_str(proto_balancer_table
-            .get("algo")
-            .and_then(|v| v.as_str())
-            .unwrap_or("min_latency"))
-            .unwrap_or(Algo::MinLatency);
+        // Interval of the eth_getBlockByNumber("latest") polling of the head_source = "poll" RPCs
+        let poll_interval_ms = reader
+            .get_usize("poll_interval_ms", 100)
+            .map(|poll_interval_ms| poll_interval_ms as u64)
+            .unwrap_or(defaults.poll_interval_ms);
+
+        // Time a websocket can be down before its RPC falls back to polling
+        let ws_fallback_after_ms = reader
+            .get_usize("ws_fallback_after_ms", 0)
+            .map(|ws_fallback_after_ms| ws_fallback_after_ms as u64)
+            .unwrap_or(defaults.ws_fallback_after_ms);
+
+        // An RPC is stale when no head arrived for stale_head_factor * block_time_ms of its chain
+        let stale_head_factor = reader
+            .get_usize("stale_head_factor", 1)
+            .map(|stale_head_factor| stale_head_factor as u64)
+            .unwrap_or(defaults.stale_head_factor);
 
+        // Identical requests in flight at the same time share a single upstream call
+        let coalesce_requests = reader
+            .get_bool("coalesce_requests")
+            .unwrap_or(defaults.coalesce_requests);
+
+        // Answer eth_blockNumber, eth_chainId and net_version from the tracked heads
+        let serve_locally = reader
+            .get_bool("serve_locally")
+            .unwrap_or(defaults.serve_locally);
+
+        // Larger request bodies are rejected with a 413
+        let max_request_size = reader
+            .get_usize("max_request_size", 1)
+            .unwrap_or(defaults.max_request_size);
+
+        // Batches are split in chunks of at most max_batch_size entries, RPCs can set a lower limit
+        let max_batch_size = reader
+            .get_usize("max_batch_size", 1)
+            .unwrap_or(defaults.max_batch_size);
+
+        // Write the RPCs added at runtime back to the config file
+        let persist = reader.get_bool("persist").unwrap_or(defaults.persist);
+
+        // Reload the config file when it changes on disk, SIGHUP always reloads it
+        let watch_config = reader.get_bool("watch_config").unwrap_or(defaults.watch_config);
+
+        // Optional [admin] table, the admin API is served under /admin on the main address
+        // unless it has its own address
+        let admin_table = match root_table.get("admin") {
+            Some(Value::Table(table)) => table,
+            Some(_) => {
+                issues.push(ConfigIssue::new("admin", "", "expected a table".to_string()));
+                &empty_table
+            },
+            None => &empty_table,
+        };
+        let mut reader = TableReader::new("admin", admin_table, &mut issues);
+        let admin_address = reader.get_parsed("address", "an address (i.e. 127.0.0.1:3001)", parse_address);
+        let admin_token = reader.get_str("token");
+        let admin_allowed_ips = reader
+            .get_str_array("allowed_ips")
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|ip| match ip.parse::<IpAddr>() {
+                Ok(ip) => Some(ip),
+                Err(_) => {
+                    reader.issue("allowed_ips", format!("invalid IP address '{}'", ip));
+                    None
+                },
+            })
+            .collect();
+
+        // Optional [chains.<chain_id>] tables
+        let chains_table = match root_table.get("chains") {
+            Some(Value::Table(table)) => table,
+            Some(_) => {
+                issues.push(ConfigIssue::new("chains", "", "expected a table".to_string()));
+                &empty_table
+            },
+            None => &empty_table,
+        };
+        let mut chains = BTreeMap::new();
+        for (chain_key, chain_value) in chains_table {
+            let table_name = format!("chains.{}", chain_key);
+            let chain_id = match chain_key.parse::<usize>() {
+                Ok(chain_id) if chain_id > 0 => chain_id,
+                _ => {
+                    issues.push(ConfigIssue::new(&table_name, "", "expected a chain id (i.e. [chains.10])".to_string()));
+                    continue;
+                },
+            };
+            let chain_table = match chain_value.as_table() {
+                Some(chain_table) => chain_table,
+                None => {
+                    issues.push(ConfigIssue::new(&table_name, "", "expected a table".to_string()));
+                    continue;
+                },
+            };
+            let mut reader = TableReader::new(&table_name, chain_table, &mut issues);
+            let chain_settings = ChainSettings {
+                block_time_ms: reader
+                    .get_usize("block_time_ms", 1)
+                    .map(|block_time_ms| block_time_ms as u64),
+                max_block_lag: reader
+                    .get_usize("max_block_lag", 0)
+                    .map(|max_block_lag| max_block_lag as u64),
+                block_lag_policy: reader
+                    .get_parsed("block_lag_policy", "exclude or last_resort", BlockLagPolicy::from_str)
+                    .unwrap_or_default(),
+            };
+            chains.insert(chain_id, chain_settings);
+        }
+
+        // Optional [cache] table, only the methods listed in [cache.methods] are cached
+        let cache_table = match root_table.get("cache") {
+            Some(Value::Table(table)) => table,
+            Some(_) => {
+                issues.push(ConfigIssue::new("cache", "", "expected a table".to_string()));
+                &empty_table
+            },
+            None => &empty_table,
+        };
+        let mut reader = TableReader::new("cache", cache_table, &mut issues);
+        let max_entries = reader
+            .get_usize("max_entries", 1)
+            .unwrap_or(defaults.cache.max_entries);
+        let methods_table = match cache_table.get("methods") {
+            Some(Value::Table(table)) => table,
+            Some(_) => {
+                reader.issue("methods", "expected a table".to_string());
+                &empty_table
+            },
+            None => &empty_table,
+        };
+        let mut reader = TableReader::new("cache.methods", methods_table, &mut issues);
+        let mut methods = HashMap::new();
+        for (method, policy_value) in methods_table {
+            // "forever", "head" or a ttl in ms
+            let policy = reader.typed(method, Some(policy_value), "\"forever\", \"head\" or a ttl in ms", |v| match v {
+                Value::String(policy) => CachePolicy::from_str(policy).ok(),
+                Value::Integer(ttl_ms) if *ttl_ms > 0 => Some(CachePolicy::Ttl(*ttl_ms as u64)),
+                _ => None,
+            });
+            if let Some(policy) = policy {
+                methods.insert(method.clone(), policy);
+            }
+        }
+        let cache = CacheSettings { max_entries, methods };
+
+        // Optional [[routing_rule]] tables, the first rule matching the method of a request applies
+        let routing_rule_tables = match root_table.get("routing_rule") {
+            Some(Value::Array(tables)) => tables.as_slice(),
+            Some(_) => {
+                issues.push(ConfigIssue::new("routing_rule", "", "expected an array of tables ([[routing_rule]])".to_string()));
+                &[]
+            },
+            None => &[],
+        };
+        let mut routing_rules = Vec::new();
+        for (position, rule_value) in routing_rule_tables.iter().enumerate() {
+            let table_name = format!("routing_rule.{}", position);
+            let rule_table = match rule_value.as_table() {
+                Some(rule_table) => rule_table,
+                None => {
+                    issues.push(ConfigIssue::new(&table_name, "", "expected a table".to_string()));
+                    continue;
+                },
+            };
+            let mut reader = TableReader::new(&table_name, rule_table, &mut issues);
+            let methods = reader.get_str_array("methods").unwrap_or_default();
+            if methods.is_empty() {
+                reader.issue("methods", "expected at least one method (i.e. [\"trace_*\"])".to_string());
+            }
+            let tags = reader.get_str_array("tags").unwrap_or_default();
+            if let Some(tag) = tags.iter().find(|tag| !is_valid_tag(tag)) {
+                reader.issue("tags", format!("invalid tag '{}', tags cannot be empty, only digits, start with ~ or contain commas or spaces", tag));
+            }
+            routing_rules.push(RoutingRule {
+                methods,
+                tags,
+                rpc_location: reader.get_parsed("rpc_location", "Local or External", RpcLocation::from_str),
+                algo: reader.get_parsed("algo", "min_latency, round_robin or broadcast", Algo::from_str),
+                timeout_ms: reader
+                    .get_usize("timeout_ms", 1)
+                    .map(|timeout_ms| timeout_ms as u64),
+                max_retries: reader.get_usize("max_retries", 0),
+            });
+        }
 
         // Parse all the other tables as RPCs and put them in a Vec<Rpc>
         let mut rpc_list: Vec<Rpc> = Vec::new();
-        for table_name in table_names {
-            if table_name != "proto_balancer" {
-                let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
-
-                let url = String::from(rpc_table
-                    .get("url")
-                    .expect("\x1b[31mErr:\x1b[0m Missing url from an RPC!")
-                    .as_str()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse url as str!"));
-
-                let ws_url = String::from(rpc_table
-                    .get("ws_url")
-                    .expect("\x1b[31mErr:\x1b[0m Missing ws_url from an RPC!")
-                    .as_str()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse ws_url as str!"));
-
-                let chain_id = rpc_table
-                    .get("chain_id")
-                    .expect("\x1b[31mErr:\x1b[0m Missing chain_id from an RPC!")
-                    .as_integer()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse chain_id as integer!")
-                    as usize;
-
-                let rpc_location = match rpc_table
-                    .get("rpc_location")
-                    .expect("\x1b[31mErr:\x1b[0m Missing rpc_location from an RPC!")
-                    .as_str()
-                    .expect("\x1b[31mErr:\x1b[0m Could not parse rpc_location as String!")
-                    .to_lowercase()
-                    .as_str()
-                {
-                    "local" => RpcLocation::Local,
-                    "external" => RpcLocation::External,
-                    _ => panic!("\x1b[31mErr:\x1b[0m Invalid rpc_location!"),
-                };
-
-                let rpc = Rpc::new(url, ws_url, chain_id, rpc_location, stats_vec_size).await;
+        let mut rpc_table_names: Vec<(String, &str)> = Vec::new();
+        for (table_name, table_value) in root_table {
+            if RESERVED_TABLES.contains(&table_name.as_str()) {
+                continue;
+            }
+            let rpc_table = match table_value.as_table() {
+                Some(rpc_table) => rpc_table,
+                None => {
+                    issues.push(ConfigIssue::new(table_name, "", "expected an RPC table".to_string()));
+                    continue;
+                },
+            };
+            let mut reader = TableReader::new(table_name, rpc_table, &mut issues);
+
+            let url = reader.required_str("url");
+            let ws_url = reader.get_str("ws_url");
+            let chain_id = reader.required_usize("chain_id", 1);
+            let rpc_location = reader.required_parsed("rpc_location", "Local or External", RpcLocation::from_str);
+
+            // Disabled rpcs keep tracking heads but are not routed to
+            let enabled = reader.get_bool("enabled").unwrap_or(true);
+            let max_batch_size = reader.get_usize("max_batch_size", 1);
+            let tags = reader.get_str_array("tags").unwrap_or_default();
+            if let Some(tag) = tags.iter().find(|tag| !is_valid_tag(tag)) {
+                reader.issue("tags", format!("invalid tag '{}', tags cannot be empty, only digits, start with ~ or contain commas or spaces", tag));
+            }
+            // Rpcs without a history_depth are probed
+            let history_depth = reader.typed("history_depth", rpc_table.get("history_depth"), "\"archive\" or a number of blocks", |v| match v {
+                Value::String(history_depth) if history_depth.eq_ignore_ascii_case("archive") => Some(HistoryDepth::Archive),
+                Value::Integer(blocks) if *blocks > 0 => Some(HistoryDepth::Blocks(*blocks as u64)),
+                _ => None,
+            });
+
+            // Rpcs without a ws_url can only poll their head
+            let head_source = reader.get_parsed("head_source", "ws or poll", HeadSource::from_str);
+            if head_source == Some(HeadSource::Ws) && ws_url.is_none() {
+                reader.issue("head_source", "head_source = \"ws\" requires a ws_url".to_string());
+            }
+
+            if let Some(url) = &url {
+                if let Some((_, other_table)) = rpc_table_names.iter().find(|(other_url, _)| other_url == url) {
+                    reader.issue("url", format!("duplicate url, already used by [{}]", other_table));
+                }
+                rpc_table_names.push((url.clone(), table_name));
+            }
+
+            if let (Some(url), Some(chain_id), Some(rpc_location)) = (url, chain_id, rpc_location) {
+                let mut rpc = Rpc::new(url, ws_url, chain_id, rpc_location, stats_vec_size).await;
+                rpc.enabled = enabled;
+                rpc.max_batch_size = max_batch_size;
+                rpc.tags = tags;
+                rpc.history_depth = history_depth.unwrap_or_default();
+                if let Some(head_source) = head_source {
+                    rpc.head_source = head_source;
+                }
                 rpc_list.push(rpc);
             }
         }
 
-        Settings {
+        if !issues.is_empty() {
+            return Err(ConfigError::Invalid(issues));
+        }
+
+        Ok(Settings {
             rpc_list,
             address,
-            log_level: log_level.to_string(),
+            log_level,
             stats_vec_size,
             algo,
+            broadcast_size,
+            broadcast_selection,
+            ejection,
+            poll_interval_ms,
+            ws_fallback_after_ms,
+            stale_head_factor,
+            coalesce_requests,
+            serve_locally,
+            max_request_size,
+            max_batch_size,
+            chains,
+            cache,
+            routing_rules,
+            config_path: String::from("rpc_config.toml"),
+            persist,
+            watch_config,
+            admin_address,
+            admin_token,
+            admin_allowed_ips,
+        })
+    }
+
+    pub async fn check_config(matches: &ArgMatches) -> i32 {
+        // Validate the config file without starting the balancer, returns the process exit code
+        match Settings::new(matches).await {
+            Ok(settings) => {
+                println!("{} is valid ({} RPCs)", settings.config_path, settings.rpc_list.len());
+                0
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            },
         }
     }
 
     pub fn create_match() -> Command {
+        let config_arg = Arg::new("config")
+            .long("config")
+            .short('c')
+            .num_args(1..)
+            .default_value("rpc_config.toml")
+            .help("TOML config file for load balancer prototype.");
+
         Command::new("proto_balancer")
             .version("0.1.0")
             .author("pedroid999 <pedrete999@gmail.com> and contributors")
             .about("load balancer prototype.")
-            .arg(Arg::new("config")
-                .long("config")
-                .short('c')
-                .num_args(1..)
-                .default_value("rpc_config.toml")
-                .help("TOML config file for load balancer prototype."))
-    }
-}
\ No newline at end of file
+            .arg(config_arg.clone())
+            .subcommand(Command::new("check-config")
+                .about("Validate the config file and exit non-zero if it has problems.")
+                .arg(config_arg))
+    }
+}
+
+pub fn parse_address(address: &str) -> Result<SocketAddr, std::net::AddrParseError> {
+    // Build the SocketAddr
+    let port = 3000;
+    // Replace `localhost` if it exists
+    let address = address.replace("localhost", "127.0.0.1");
+    // If the address contains `:` don't concatenate the port and just pass the address
+    let address = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, port)
+    };
+    address.parse::<SocketAddr>()
+}
+
+// Reads the keys of a config table, recording a ConfigIssue for every missing or invalid key
+pub struct TableReader<'a> {
+    name: &'a str,
+    table: &'a Table,
+    issues: &'a mut Vec<ConfigIssue>,
+}
+
+impl<'a> TableReader<'a> {
+    pub fn new(name: &'a str, table: &'a Table, issues: &'a mut Vec<ConfigIssue>) -> Self {
+        Self { name, table, issues }
+    }
+
+    pub fn issue(&mut self, key: &str, message: String) {
+        self.issues.push(ConfigIssue::new(self.name, key, message));
+    }
+
+    fn required(&mut self, key: &str) -> Option<&'a Value> {
+        let value = self.table.get(key);
+        if value.is_none() {
+            self.issue(key, "missing key".to_string());
+        }
+        value
+    }
+
+    fn typed<T>(&mut self, key: &str, value: Option<&'a Value>, expected: &str,
+                convert: impl FnOnce(&'a Value) -> Option<T>) -> Option<T> {
+        let value = value?;
+        let converted = convert(value);
+        if converted.is_none() {
+            self.issue(key, format!("expected {}, found {}", expected, value));
+        }
+        converted
+    }
+
+    pub fn get_str(&mut self, key: &str) -> Option<String> {
+        let value = self.table.get(key);
+        self.typed(key, value, "a string", |v| v.as_str().map(String::from))
+    }
+
+    pub fn required_str(&mut self, key: &str) -> Option<String> {
+        let value = self.required(key);
+        self.typed(key, value, "a string", |v| v.as_str().map(String::from))
+    }
+
+    pub fn get_bool(&mut self, key: &str) -> Option<bool> {
+        let value = self.table.get(key);
+        self.typed(key, value, "a boolean", |v| v.as_bool())
+    }
+
+    pub fn get_usize(&mut self, key: &str, min: usize) -> Option<usize> {
+        let value = self.table.get(key);
+        self.typed(key, value, &format!("an integer >= {}", min), |v| v
+            .as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .filter(|i| *i >= min))
+    }
+
+    pub fn get_f64(&mut self, key: &str, min: f64, max: f64) -> Option<f64> {
+        let value = self.table.get(key);
+        self.typed(key, value, &format!("a number between {} and {}", min, max), |v| v
+            .as_float()
+            .or_else(|| v.as_integer().map(|i| i as f64))
+            .filter(|f| (min..=max).contains(f)))
+    }
+
+    pub fn required_usize(&mut self, key: &str, min: usize) -> Option<usize> {
+        self.required(key)?;
+        self.get_usize(key, min)
+    }
+
+    pub fn get_str_array(&mut self, key: &str) -> Option<Vec<String>> {
+        let value = self.table.get(key);
+        self.typed(key, value, "an array of strings", |v| v
+            .as_array()?
+            .iter()
+            .map(|item| item.as_str().map(String::from))
+            .collect())
+    }
+
+    pub fn get_parsed<T, E>(&mut self, key: &str, expected: &str,
+                            parse: impl FnOnce(&str) -> Result<T, E>) -> Option<T> {
+        let value = self.get_str(key)?;
+        match parse(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.issue(key, format!("invalid value '{}', expected {}", value, expected));
+                None
+            },
+        }
+    }
+
+    pub fn required_parsed<T, E>(&mut self, key: &str, expected: &str,
+                                 parse: impl FnOnce(&str) -> Result<T, E>) -> Option<T> {
+        self.required(key)?;
+        self.get_parsed(key, expected, parse)
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{
+    archive::functions::probe_history,
+    cache::functions::{
+        invalidate_head_entries,
+        on_new_head,
+    },
+    config::types::Settings,
+    head::types::{
+        BlockHeader,
+        FORK_QUORUM,
+        STALE_HEAD_CHECK_INTERVAL_MS,
+    },
+    metrics::functions::{record_reorg, upstream_label},
+    rpc::{
+        functions::{
+            rpc_host,
+            send_request,
+        },
+        types::{
+            HeadSource,
+            Rpc,
+        },
+    },
+    websocket::functions::start_rpc_websocket,
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+pub async fn start_head_tracking(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize, config: Arc<RwLock<Settings>>) {
+    // Subscribe to new heads when the rpc uses its websocket, the poller covers the rest
+    let ws_url = {
+        let rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard
+            .iter()
+            .find(|rpc| rpc.id == rpc_id)
+            .filter(|rpc| rpc.head_source == HeadSource::Ws)
+            .and_then(|rpc| rpc.ws_url.clone())
+    };
+    if let Some(ws_url) = ws_url {
+        start_rpc_websocket(rpc_list.clone(), rpc_id, ws_url).await;
+    }
+    tokio::task::spawn(probe_history(rpc_list.clone(), rpc_id));
+    tokio::task::spawn(poll_head(rpc_list, rpc_id, config));
+}
+
+pub async fn poll_head(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize, config: Arc<RwLock<Settings>>) {
+    loop {
+        let (poll_interval_ms, ws_fallback_after_ms) = {
+            let config_guard = config.read().await;
+            (config_guard.poll_interval_ms, config_guard.ws_fallback_after_ms)
+        };
+        tokio::time::sleep(Duration::from_millis(poll_interval_ms)).await;
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let url = {
+            let mut rpc_guard = rpc_list.lock().unwrap();
+            // The rpc was removed, stop polling
+            let rpc = match rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id) {
+                Some(rpc) => rpc,
+                None => return,
+            };
+            let should_poll = match rpc.head_source {
+                HeadSource::Poll => true,
+                HeadSource::Ws => !rpc.ws_connected && now.saturating_sub(rpc.ws_disconnected_ts) >= ws_fallback_after_ms,
+            };
+            if !should_poll {
+                rpc.poll_ok = false;
+                continue;
+            }
+            rpc.url.clone()
+        };
+
+        let head = fetch_latest_head(&url).await;
+        let current_ts = chrono::Utc::now().timestamp_millis() as u64;
+        let mut rpc_guard = rpc_list.lock().unwrap();
+        let rpc = match rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id) {
+            Some(rpc) => rpc,
+            None => return,
+        };
+        match head {
+            Ok(head) => {
+                if !rpc.poll_ok {
+                    match rpc.head_source {
+                        HeadSource::Ws => info!("WS of {} down for more than {} ms, polling its head",
+                            rpc_host(&rpc.url), ws_fallback_after_ms),
+                        HeadSource::Poll => info!("Polling heads of {}", rpc_host(&rpc.url)),
+                    }
+                }
+                rpc.poll_ok = true;
+                rpc.record_head(head.number, head.timestamp * 1000, current_ts);
+                track_block(rpc, head);
+            },
+            Err(e) => {
+                if rpc.poll_ok {
+                    warn!("Error polling the head of {}: {}", rpc_host(&rpc.url), e);
+                }
+                rpc.poll_ok = false;
+            },
+        }
+    }
+}
+
+pub async fn watch_stale_heads(rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(STALE_HEAD_CHECK_INTERVAL_MS));
+    loop {
+        interval.tick().await;
+        let (chains, stale_head_factor) = {
+            let config_guard = config.read().await;
+            (config_guard.chains.clone(), config_guard.stale_head_factor)
+        };
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let mut rpc_guard = rpc_list.lock().unwrap();
+        for rpc in rpc_guard.iter_mut() {
+            // Only the chains with a block_time_ms are watched
+            let max_head_age_ms = match chains.get(&rpc.chain_id).and_then(|chain| chain.block_time_ms) {
+                Some(block_time_ms) => block_time_ms * stale_head_factor,
+                None => {
+                    rpc.head_overdue = false;
+                    continue;
+                },
+            };
+            // current_ts is the arrival time of the last new head
+            let head_age_ms = now.saturating_sub(rpc.current_ts);
+            let head_overdue = head_age_ms > max_head_age_ms;
+            if head_overdue != rpc.head_overdue {
+                match head_overdue {
+                    true if rpc.current_ts == 0 => info!("No head received yet from {}, marking it stale", rpc_host(&rpc.url)),
+                    true => warn!("No new head from {} for {} ms, marking it stale", rpc_host(&rpc.url), head_age_ms),
+                    false => info!("New head from {}, no longer stale", rpc_host(&rpc.url)),
+                }
+            }
+            rpc.head_overdue = head_overdue;
+        }
+    }
+}
+
+pub fn track_block(rpc: &mut Rpc, block: BlockHeader) {
+    // Add the block to the rpc history and report the reorgs it reveals
+    let block_number = block.number;
+    if let Some(reorg_depth) = rpc.record_block(block) {
+        warn!("Reorg of depth {} on chain {} reported by {} at block {}",
+            reorg_depth, rpc.chain_id, rpc_host(&rpc.url), block_number);
+        record_reorg(rpc.chain_id, &upstream_label(rpc));
+        // Responses cached for the replaced head are no longer valid
+        invalidate_head_entries(rpc.chain_id);
+    }
+    on_new_head(rpc.chain_id, block_number);
+}
+
+pub async fn watch_forks(rpc_list: Arc<Mutex<Vec<Rpc>>>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(STALE_HEAD_CHECK_INTERVAL_MS));
+    loop {
+        interval.tick().await;
+        let mut rpc_guard = rpc_list.lock().unwrap();
+        let minority_forks = find_minority_forks(&rpc_guard);
+        for rpc in rpc_guard.iter_mut() {
+            let minority_fork = minority_forks.contains(&rpc.id);
+            if minority_fork != rpc.minority_fork {
+                match minority_fork {
+                    true => warn!("{} is on a minority fork of chain {}, deprioritizing it", rpc_host(&rpc.url), rpc.chain_id),
+                    false => info!("{} is back on the majority fork of chain {}", rpc_host(&rpc.url), rpc.chain_id),
+                }
+            }
+            rpc.minority_fork = minority_fork;
+        }
+    }
+}
+
+pub fn find_minority_forks(rpc_list: &[Rpc]) -> Vec<usize> {
+    // Hashes reported by the rpcs of each chain at each height
+    let mut chain_hashes: HashMap<usize, BTreeMap<u64, Vec<&str>>> = HashMap::new();
+    for rpc in rpc_list {
+        let heights = chain_hashes.entry(rpc.chain_id).or_default();
+        for block in &rpc.recent_blocks {
+            heights.entry(block.number).or_default().push(block.hash.as_str());
+        }
+    }
+
+    let mut minority_forks = Vec::new();
+    for rpc in rpc_list {
+        let heights = &chain_hashes[&rpc.chain_id];
+        // Compare the rpc on the highest of its blocks that enough rpcs reported
+        let decisive_block = rpc
+            .recent_blocks
+            .iter()
+            .rev()
+            .find(|block| heights[&block.number].len() >= FORK_QUORUM);
+        if let Some(block) = decisive_block {
+            let hashes = &heights[&block.number];
+            let agreeing = hashes.iter().filter(|hash| **hash == block.hash).count();
+            // Only a strict majority of other hashes makes it a minority fork
+            let majority_hash_count = hashes
+                .iter()
+                .map(|hash| hashes.iter().filter(|other| *other == hash).count())
+                .max()
+                .unwrap_or_default();
+            if agreeing < majority_hash_count && majority_hash_count * 2 > hashes.len() {
+                minority_forks.push(rpc.id);
+            }
+        }
+    }
+    minority_forks
+}
+
+pub async fn fetch_latest_head(url: &str) -> Result<BlockHeader, String> {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": "eth_getBlockByNumber",
+        "params": ["latest", false],
+        "id": 1,
+    });
+    let response = send_request(url.to_string(), request).await.map_err(|e| e.format_error())?;
+    serde_json::from_str::<Value>(&response)
+        .ok()
+        .and_then(|value| value.get("result").and_then(BlockHeader::from_value))
+        .ok_or_else(|| format!("invalid eth_getBlockByNumber response: {}", response))
+}
+
+pub async fn local_head_response(rpc_list: &Arc<Mutex<Vec<Rpc>>>, chain_id: usize, json_value: &Value,
+                                 config: &Arc<RwLock<Settings>>) -> Option<String> {
+    if !config.read().await.serve_locally {
+        return None;
+    }
+
+    // Only answer for chains with an enabled rpc, the others get the usual error from forwarding
+    let rpc_guard = rpc_list.lock().unwrap();
+    let chain_rpcs: Vec<&Rpc> = rpc_guard.iter().filter(|rpc| rpc.chain_id == chain_id && rpc.enabled).collect();
+    if chain_rpcs.is_empty() {
+        return None;
+    }
+
+    let result = match json_value["method"].as_str()? {
+        "eth_chainId" => format!("0x{:x}", chain_id),
+        "net_version" => chain_id.to_string(),
+        "eth_blockNumber" => {
+            // Stale or forked heads are not trusted, the request is forwarded instead
+            let chain_head = chain_rpcs
+                .iter()
+                .filter(|rpc| !rpc.is_deprioritized())
+                .map(|rpc| rpc.last_block)
+                .max()
+                .filter(|last_block| *last_block > 0)?;
+            format!("0x{:x}", chain_head)
+        },
+        _ => return None,
+    };
+    Some(json!({
+        "jsonrpc": "2.0",
+        "id": json_value["id"],
+        "result": result,
+    }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(id: usize, chain_id: usize, hashes: &[(u64, &str)]) -> Rpc {
+        let mut rpc = Rpc { id, chain_id, ..Rpc::default() };
+        for (number, hash) in hashes {
+            rpc.recent_blocks.push_back(BlockHeader { number: *number, timestamp: 0, hash: hash.to_string(), parent_hash: String::new() });
+        }
+        rpc
+    }
+
+    #[test]
+    fn find_minority_forks_flags_the_rpcs_outvoted_by_a_strict_majority() {
+        let rpc_list = [
+            rpc(1, 10, &[(5, "0xa"), (6, "0xb")]),
+            rpc(2, 10, &[(5, "0xa"), (6, "0xb")]),
+            rpc(3, 10, &[(5, "0xa"), (6, "0xf")]),
+            // Ahead of the others, judged on the highest block enough rpcs reported
+            rpc(4, 10, &[(6, "0xb"), (7, "0xc")]),
+        ];
+        assert_eq!(find_minority_forks(&rpc_list), vec![3]);
+    }
+
+    #[test]
+    fn find_minority_forks_needs_a_quorum_and_a_majority() {
+        // Below the quorum nobody is flagged
+        let rpc_list = [rpc(1, 10, &[(5, "0xa")]), rpc(2, 10, &[(5, "0xb")])];
+        assert!(find_minority_forks(&rpc_list).is_empty());
+        // Split without a strict majority
+        let rpc_list = [
+            rpc(1, 10, &[(5, "0xa")]),
+            rpc(2, 10, &[(5, "0xa")]),
+            rpc(3, 10, &[(5, "0xb")]),
+            rpc(4, 10, &[(5, "0xb")]),
+        ];
+        assert!(find_minority_forks(&rpc_list).is_empty());
+    }
+
+    #[test]
+    fn find_minority_forks_compares_rpcs_of_the_same_chain() {
+        let rpc_list = [
+            rpc(1, 10, &[(5, "0xa")]),
+            rpc(2, 10, &[(5, "0xa")]),
+            rpc(3, 10, &[(5, "0xa")]),
+            rpc(4, 8453, &[(5, "0xb")]),
+        ];
+        assert!(find_minority_forks(&rpc_list).is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+use serde_json::Value;
+
+// Interval between two eth_getBlockByNumber("latest") polls
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+// Time the websocket of a head_source = "ws" rpc can be down before polling takes over
+pub const DEFAULT_WS_FALLBACK_AFTER_MS: u64 = 10000;
+// Number of block times without a new head after which an rpc is stale
+pub const DEFAULT_STALE_HEAD_FACTOR: u64 = 3;
+// Interval between two checks of the stale head watchdog
+pub const STALE_HEAD_CHECK_INTERVAL_MS: u64 = 500;
+// Number of latest blocks kept per rpc to detect reorgs and forks
+pub const BLOCK_HISTORY_SIZE: usize = 64;
+// Minimum number of rpcs reporting a height to tell which of them are on a minority fork
+pub const FORK_QUORUM: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub number: u64,
+    pub timestamp: u64, // in seconds, as reported by the node
+    pub hash: String,
+    pub parent_hash: String,
+}
+
+impl BlockHeader {
+    // Parse a block from a newHeads notification or an eth_getBlockByNumber result
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let hex_field = |key: &str| value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .and_then(|v| u64::from_str_radix(v.trim_start_matches("0x"), 16).ok());
+        let str_field = |key: &str| value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+            .unwrap_or_default();
+
+        Some(Self {
+            number: hex_field("number")?,
+            timestamp: hex_field("timestamp")?,
+            hash: str_field("hash"),
+            parent_hash: str_field("parentHash"),
+        })
+    }
+}
@@ -1,11 +1,31 @@
+mod admin;
+mod archive;
+mod cache;
+mod coalesce;
 mod config;
+mod head;
+mod metrics;
+mod routing;
 mod rpc;
 mod sort;
+mod stats;
 mod websocket;
 
 use crate::{
-    config::types::Settings,
-    websocket::types::RpcWebSocket,
+    admin::functions::serve_admin,
+    cache::functions::configure_cache,
+    config::{
+        functions::{
+            apply_log_level,
+            watch_config,
+        },
+        types::Settings,
+    },
+    head::functions::{
+        start_head_tracking,
+        watch_forks,
+        watch_stale_heads,
+    },
     rpc::functions::forward_json_rpc_request,
 };
 
@@ -16,7 +36,7 @@ use std::sync::{Arc, Mutex};
 use tokio::net::TcpListener;
 use tokio::sync::{RwLock};
 use env_logger::Builder;
-use log::{error, info};
+use log::{error, info, warn};
 use log::LevelFilter;
 use std::str::FromStr;
 use env_logger::TimestampPrecision::Millis;
@@ -36,53 +56,78 @@ lazy_static! {
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // Get all the cli args and set them
-    let config = Arc::new(RwLock::new(Settings::new(Settings::create_match()).await));
+    let matches = Settings::create_match().get_matches();
+    if let Some(check_matches) = matches.subcommand_matches("check-config") {
+        std::process::exit(Settings::check_config(check_matches).await);
+    }
+    let settings = match Settings::new(&matches).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = Arc::new(RwLock::new(settings));
 
     // Copy the configuration values we need
-    let (addr, log_level, stat_vec_size, algo) = {
+    let (addr, log_level, admin_addr) = {
         let config_guard = config.read().await;
-        (config_guard.address, config_guard.log_level.clone(), config_guard.stats_vec_size, config_guard.algo.clone())
-
+        (config_guard.address, config_guard.log_level.clone(), config_guard.admin_address)
     };
 
     Builder::new()
-        .filter_level(LevelFilter::from_str(log_level.as_str()).unwrap()) // Set the log level
+        .filter_level(LevelFilter::Trace) // Let everything through, the max level is changed at runtime
         .write_style(env_logger::WriteStyle::Always) // Enable output to stdout
         .format_timestamp(Some(Millis))
         .init();
+    apply_log_level(LevelFilter::from_str(log_level.as_str()).unwrap()); // Set the log level
+    configure_cache(&config.read().await.cache); // Size the response cache
 
     // Make a mutex rpc list
     let rpc_list_rwlock = Arc::new(Mutex::new(config.read().await.rpc_list.clone()));
 
-    // start all rpc websockets with tokio::task
-    let len = rpc_list_rwlock.lock().unwrap().len();
-    for index in 0..len {
-        let rpc_list_rwlock_clone = rpc_list_rwlock.clone();
-        let rpc_websocket =
-            RpcWebSocket::new(rpc_list_rwlock_clone.lock().unwrap()[index].ws_url.clone());
-        let mut rpc_websocket_clone = rpc_websocket.await.clone();
-        info!("Starting web sockets {}", index);
-        tokio::task::spawn(async move {
-            rpc_websocket_clone
-                .start_rpc(rpc_list_rwlock_clone, index)
-                .await;
-        });
+    // start the head tracking (websocket and polling) of all rpcs with tokio::task
+    let rpc_ids: Vec<usize> = rpc_list_rwlock
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|rpc| rpc.id)
+        .collect();
+    for rpc_id in rpc_ids {
+        info!("Starting head tracking {}", rpc_id);
+        start_head_tracking(rpc_list_rwlock.clone(), rpc_id, config.clone()).await;
+    }
+
+    // Mark the rpcs that stopped receiving heads as stale
+    tokio::task::spawn(watch_stale_heads(rpc_list_rwlock.clone(), config.clone()));
+    // Deprioritize the rpcs following a minority fork
+    tokio::task::spawn(watch_forks(rpc_list_rwlock.clone()));
+
+    // Reload the config file on SIGHUP, or when it changes if watch_config is enabled
+    tokio::task::spawn(watch_config(rpc_list_rwlock.clone(), config.clone()));
+
+    if config.read().await.admin_token.is_none() && config.read().await.admin_allowed_ips.is_empty() {
+        warn!("Admin API disabled, configure an [admin] token or allowed_ips to enable it");
+    }
+    // Serve the admin API on its own listener when an admin address is configured
+    if let Some(admin_addr) = admin_addr {
+        let admin_listener = TcpListener::bind(admin_addr).await?;
+        info!("Admin API listening on {}", admin_addr);
+        tokio::task::spawn(serve_admin(admin_listener, rpc_list_rwlock.clone(), config.clone()));
     }
 
     let listener = TcpListener::bind(addr).await?;
 
     // We start a loop to continuously accept incoming connections
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, remote_addr) = listener.accept().await?;
         // Use and adapter to access something implementing 'tokio::io' traits as if they implement
         // 'hyper::rt' IO traits.
         let io = TokioIo::new(stream);
         let rpc_list_rwlock_clone = rpc_list_rwlock.clone();
-        let algo = algo.clone();
+        let config_clone = config.clone();
         // Spawn a tokio task to serve multiple connections concurrently.
         tokio::task::spawn(async move {
-            let rpc_list_rwlock_clone = rpc_list_rwlock_clone.clone();
-            let algo = algo.clone();
             let start = std::time::Instant::now();
             // Finally, we bind the incoming connection to our 'forward_json_rpc_request' service
             if let Err(err) = http1::Builder::new()
@@ -91,8 +136,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                     io,
                     service_fn(|req| {
                         let rpc_list_rwlock_clone = rpc_list_rwlock_clone.clone();
-                        let algo = algo.clone();
-                        forward_json_rpc_request(req, rpc_list_rwlock_clone, stat_vec_size, algo)
+                        let config_clone = config_clone.clone();
+                        forward_json_rpc_request(req, remote_addr, rpc_list_rwlock_clone, config_clone)
                     }),
                 )
                 .with_upgrades()
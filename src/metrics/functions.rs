@@ -0,0 +1,189 @@
+use crate::{
+    metrics::types::{
+        CACHE_REQUESTS_TOTAL,
+        COALESCED_REQUESTS_TOTAL,
+        INTRA_LATENCY_SECONDS,
+        REORGS_TOTAL,
+        REQUESTS_TOTAL,
+        SRV_LATENCY_SECONDS,
+        UPSTREAM_BLOCK_LAG,
+        UPSTREAM_EJECTED,
+        UPSTREAM_EJECTIONS,
+        UPSTREAM_LAST_BLOCK,
+        UPSTREAM_MINORITY_FORK,
+        UPSTREAM_WS_CONNECTED,
+        KNOWN_METHODS,
+    },
+    rpc::{
+        functions::rpc_host,
+        types::Rpc,
+    },
+    sort::functions::chain_head,
+};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::Response;
+use prometheus::core::Collector;
+use prometheus::{Encoder, TextEncoder};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub fn get_metrics(rpc_list: Arc<Mutex<Vec<Rpc>>>) -> Result<Response<String>, hyper::Error> {
+    let rpc_list_copy = {
+        let rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard.clone()
+    };
+    update_upstream_gauges(&rpc_list_copy);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(String::from_utf8(buffer).unwrap())
+        .unwrap())
+}
+
+pub fn update_upstream_gauges(rpc_list: &[Rpc]) {
+    // The lag is measured from the chain head routing uses, the enabled rpcs with a trusted head
+    let mut chain_rpcs: HashMap<usize, Vec<&Rpc>> = HashMap::new();
+    for rpc in rpc_list.iter().filter(|rpc| rpc.enabled) {
+        chain_rpcs.entry(rpc.chain_id).or_default().push(rpc);
+    }
+    let chain_heads: HashMap<usize, u64> = chain_rpcs
+        .iter()
+        .map(|(chain_id, chain_rpc_list)| (*chain_id, chain_head(chain_rpc_list)))
+        .collect();
+
+    // Reset so removed upstreams do not keep exporting their last values
+    UPSTREAM_LAST_BLOCK.reset();
+    UPSTREAM_BLOCK_LAG.reset();
+    UPSTREAM_WS_CONNECTED.reset();
+    UPSTREAM_MINORITY_FORK.reset();
+    UPSTREAM_EJECTED.reset();
+    UPSTREAM_EJECTIONS.reset();
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    for rpc in rpc_list {
+        let chain_id = rpc.chain_id.to_string();
+        let upstream = upstream_label(rpc);
+        let labels = [chain_id.as_str(), upstream.as_str()];
+        UPSTREAM_LAST_BLOCK.with_label_values(&labels).set(rpc.last_block as i64);
+        UPSTREAM_BLOCK_LAG
+            .with_label_values(&labels)
+            .set(chain_heads.get(&rpc.chain_id).copied().unwrap_or_default().saturating_sub(rpc.last_block) as i64);
+        UPSTREAM_WS_CONNECTED.with_label_values(&labels).set(rpc.ws_connected as i64);
+        UPSTREAM_MINORITY_FORK.with_label_values(&labels).set(rpc.minority_fork as i64);
+        UPSTREAM_EJECTED.with_label_values(&labels).set(rpc.breaker.is_open(now) as i64);
+        UPSTREAM_EJECTIONS.with_label_values(&labels).set(rpc.breaker.ejections as i64);
+    }
+}
+
+pub fn remove_upstream_metrics(chain_id: usize, upstream: &str) {
+    // The counters and histograms of a removed rpc, or of its previous chain, would be exported forever.
+    // A series the rpc never recorded is not an error
+    let chain_id = chain_id.to_string();
+    let labels = [chain_id.as_str(), upstream];
+    let _ = INTRA_LATENCY_SECONDS.remove_label_values(&labels);
+    let _ = SRV_LATENCY_SECONDS.remove_label_values(&labels);
+    let _ = REORGS_TOTAL.remove_label_values(&labels);
+    // The requests are also labeled by method and outcome, every series of the rpc is removed
+    for metric_family in REQUESTS_TOTAL.collect() {
+        for metric in metric_family.get_metric() {
+            let labels: HashMap<&str, &str> = metric.get_label().iter().map(|label| (label.get_name(), label.get_value())).collect();
+            if labels.get("chain_id") == Some(&chain_id.as_str()) && labels.get("upstream") == Some(&upstream) {
+                let _ = REQUESTS_TOTAL.remove(&labels);
+            }
+        }
+    }
+}
+
+pub fn record_reorg(chain_id: usize, upstream: &str) {
+    REORGS_TOTAL
+        .with_label_values(&[chain_id.to_string().as_str(), upstream])
+        .inc();
+}
+
+pub fn record_cache_lookup(chain_id: usize, method: &str, result: &str) {
+    CACHE_REQUESTS_TOTAL
+        .with_label_values(&[chain_id.to_string().as_str(), method_label(method), result])
+        .inc();
+}
+
+pub fn record_coalesced_request(chain_id: usize, method: &str) {
+    COALESCED_REQUESTS_TOTAL
+        .with_label_values(&[chain_id.to_string().as_str(), method_label(method)])
+        .inc();
+}
+
+pub fn record_request(chain_id: usize, method: &str, upstream: &str, outcome: &str) {
+    REQUESTS_TOTAL
+        .with_label_values(&[chain_id.to_string().as_str(), method_label(method), upstream, outcome])
+        .inc();
+}
+
+pub fn record_latencies(chain_id: usize, upstream: &str, intra_latency: u64, srv_latency: u64) {
+    // Latencies are measured in μs, Prometheus expects seconds
+    let chain_id = chain_id.to_string();
+    let labels = [chain_id.as_str(), upstream];
+    INTRA_LATENCY_SECONDS.with_label_values(&labels).observe(intra_latency as f64 / 1_000_000.0);
+    SRV_LATENCY_SECONDS.with_label_values(&labels).observe(srv_latency as f64 / 1_000_000.0);
+}
+
+pub fn request_method(json_value: &Value) -> String {
+    match json_value {
+        Value::Array(_) => "batch".to_string(),
+        _ => json_value
+            .get("method")
+            .and_then(|m| m.as_str())
+            .unwrap_or("unknown")
+            .to_string(),
+    }
+}
+
+pub fn method_label(method: &str) -> &str {
+    // Every distinct label value is a new series kept forever, so unknown methods are grouped
+    match KNOWN_METHODS.contains(&method) {
+        true => method,
+        false => "other",
+    }
+}
+
+pub fn upstream_label(rpc: &Rpc) -> String {
+    // The id tells apart the rpcs sharing a host, i.e. with different paths or api keys
+    format!("{}#{}", rpc_host(&rpc.url), rpc.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_lag_is_measured_from_the_trusted_head_of_the_enabled_rpcs() {
+        let rpc = |id: usize, last_block: u64, enabled: bool, poll_ok: bool| Rpc {
+            id, url: format!("http://lag{}", id), chain_id: 993, last_block, enabled, poll_ok, ..Rpc::default()
+        };
+        let rpc_list = vec![rpc(1, 100, true, true), rpc(2, 120, false, true), rpc(3, 130, true, false), rpc(4, 90, true, true)];
+        update_upstream_gauges(&rpc_list);
+        let lag = |rpc: &Rpc| UPSTREAM_BLOCK_LAG.with_label_values(&["993", upstream_label(rpc).as_str()]).get();
+        assert_eq!(rpc_list.iter().map(lag).collect::<Vec<i64>>(), vec![0, 0, 0, 10]);
+    }
+
+    #[test]
+    fn remove_upstream_metrics_removes_every_series_of_the_rpc() {
+        record_request(994, "eth_call", "removed#1", "success");
+        record_request(994, "eth_getLogs", "removed#1", "retryable");
+        record_request(994, "eth_call", "kept#2", "success");
+        record_latencies(994, "removed#1", 10, 100);
+        remove_upstream_metrics(994, "removed#1");
+
+        let upstreams: Vec<String> = REQUESTS_TOTAL.collect()[0].get_metric().iter()
+            .flat_map(|metric| metric.get_label().iter().filter(|label| label.get_name() == "upstream"))
+            .map(|label| label.get_value().to_string())
+            .collect();
+        assert!(!upstreams.contains(&"removed#1".to_string()));
+        assert!(upstreams.contains(&"kept#2".to_string()));
+        assert!(SRV_LATENCY_SECONDS.remove_label_values(&["994", "removed#1"]).is_err());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod types;
+pub mod functions;
\ No newline at end of file
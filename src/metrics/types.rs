@@ -0,0 +1,164 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets,
+    register_histogram_vec,
+    register_int_counter_vec,
+    register_int_gauge_vec,
+    HistogramVec,
+    IntCounterVec,
+    IntGaugeVec,
+};
+
+// Methods with their own series in the method labels, the others come from the clients and share "other"
+pub const KNOWN_METHODS: [&str; 67] = [
+    "eth_blockNumber",
+    "eth_chainId",
+    "net_version",
+    "net_listening",
+    "net_peerCount",
+    "web3_clientVersion",
+    "web3_sha3",
+    "eth_syncing",
+    "eth_protocolVersion",
+    "eth_coinbase",
+    "eth_mining",
+    "eth_hashrate",
+    "eth_accounts",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_blobBaseFee",
+    "eth_feeHistory",
+    "eth_getBalance",
+    "eth_getCode",
+    "eth_getTransactionCount",
+    "eth_getStorageAt",
+    "eth_getProof",
+    "eth_call",
+    "eth_estimateGas",
+    "eth_createAccessList",
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getBlockReceipts",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_getUncleCountByBlockHash",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getTransactionByHash",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionReceipt",
+    "eth_getLogs",
+    "eth_newFilter",
+    "eth_newBlockFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_uninstallFilter",
+    "eth_subscribe",
+    "eth_unsubscribe",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_sendTransaction",
+    "eth_sendRawTransaction",
+    "eth_sendBundle",
+    "eth_callBundle",
+    "txpool_status",
+    "txpool_content",
+    "debug_traceTransaction",
+    "debug_traceCall",
+    "debug_traceBlockByNumber",
+    "debug_traceBlockByHash",
+    "trace_transaction",
+    "trace_block",
+    "trace_filter",
+    "trace_call",
+    "trace_replayTransaction",
+    "trace_replayBlockTransactions",
+    "trace_get",
+    "batch",
+];
+
+lazy_static! {
+    // Requests forwarded to the upstreams by chain, JSON-RPC method, upstream host#id and outcome
+    pub static ref REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "proto_balancer_requests_total",
+        "Requests forwarded by the balancer",
+        &["chain_id", "method", "upstream", "outcome"]
+    ).unwrap();
+
+    // Time spent inside the balancer before the request is sent upstream
+    pub static ref INTRA_LATENCY_SECONDS: HistogramVec = register_histogram_vec!(
+        "proto_balancer_intra_latency_seconds",
+        "Latency added by the balancer before forwarding a request",
+        &["chain_id", "upstream"],
+        exponential_buckets(0.00001, 2.0, 14).unwrap()
+    ).unwrap();
+
+    // Time spent waiting for the upstream to answer
+    pub static ref SRV_LATENCY_SECONDS: HistogramVec = register_histogram_vec!(
+        "proto_balancer_srv_latency_seconds",
+        "Latency of the upstream RPC answering a request",
+        &["chain_id", "upstream"],
+        exponential_buckets(0.0005, 2.0, 14).unwrap()
+    ).unwrap();
+
+    // Reorgs reported by the upstream head subscription or polling
+    pub static ref REORGS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "proto_balancer_reorgs_total",
+        "Chain reorgs reported by the upstream",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    // Requests answered from the response cache (hit) or forwarded to fill it (miss)
+    pub static ref CACHE_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "proto_balancer_cache_requests_total",
+        "Cacheable requests by cache lookup result",
+        &["chain_id", "method", "result"]
+    ).unwrap();
+
+    // Requests answered with the response of an identical request already in flight
+    pub static ref COALESCED_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "proto_balancer_coalesced_requests_total",
+        "Requests that shared the upstream call of an identical in-flight request",
+        &["chain_id", "method"]
+    ).unwrap();
+
+    // Gauges below are refreshed from the rpc list on every scrape
+    pub static ref UPSTREAM_LAST_BLOCK: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_last_block",
+        "Last block reported by the upstream head subscription",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_BLOCK_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_block_lag",
+        "Blocks the upstream is behind the head of its chain used for routing",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_WS_CONNECTED: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_ws_connected",
+        "Whether the upstream websocket head subscription is connected (1) or not (0)",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_MINORITY_FORK: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_minority_fork",
+        "Whether the upstream disagrees with the majority of its chain on a block hash (1) or not (0)",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_EJECTED: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_ejected",
+        "Whether the upstream is ejected by its circuit breaker (1) or routed to (0)",
+        &["chain_id", "upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_EJECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "proto_balancer_upstream_ejections",
+        "Number of times the upstream has been ejected by its circuit breaker",
+        &["chain_id", "upstream"]
+    ).unwrap();
+}
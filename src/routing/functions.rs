@@ -0,0 +1,47 @@
+use crate::{
+    routing::types::{
+        RoutingRule,
+        RpcTags,
+    },
+    rpc::types::Rpc,
+};
+
+pub fn matching_rule<'a>(routing_rules: &'a [RoutingRule], method: &str) -> Option<&'a RoutingRule> {
+    // Rules are tried in the order of the config file
+    routing_rules.iter().find(|routing_rule| routing_rule.matches(method))
+}
+
+pub fn prefer_tagged<'a>(sorted_rpc_list: Vec<&'a Rpc>, rpc_tags: &RpcTags) -> Vec<&'a Rpc> {
+    // The rpcs with the preferred tags go first, each group keeps the order of the sort
+    let (mut preferred, others): (Vec<&Rpc>, Vec<&Rpc>) = sorted_rpc_list
+        .into_iter()
+        .partition(|rpc| rpc_tags.is_preferred(rpc));
+    preferred.extend(others);
+    preferred
+}
+
+pub fn is_valid_tag(tag: &str) -> bool {
+    // Tags are sent as a comma separated list where a ~ prefix means preferred,
+    // in the path after the chain id, so a number would be read as the chain id (i.e. /10/1559)
+    !tag.is_empty()
+        && !tag.starts_with('~')
+        && !tag.contains(',')
+        && !tag.contains(char::is_whitespace)
+        && !tag.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_cannot_be_read_as_a_chain_id_or_a_list() {
+        assert!(is_valid_tag("archive"));
+        assert!(is_valid_tag("eip1559"));
+        assert!(!is_valid_tag("1559"));
+        assert!(!is_valid_tag(""));
+        assert!(!is_valid_tag("~archive"));
+        assert!(!is_valid_tag("archive,trace"));
+        assert!(!is_valid_tag("full node"));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::rpc::types::{Rpc, RpcLocation};
+use crate::sort::types::Algo;
+use std::fmt;
+
+// A [[routing_rule]] of the config file, the first rule matching the method of a request applies
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RoutingRule {
+    pub methods: Vec<String>,              // method names, a trailing * matches a prefix (i.e. debug_*)
+    pub tags: Vec<String>,                 // eligible rpcs have all these tags
+    pub rpc_location: Option<RpcLocation>, // eligible rpcs are at this location
+    pub algo: Option<Algo>,                // replaces the global algo
+    pub timeout_ms: Option<u64>,           // timeout of each upstream attempt
+    pub max_retries: Option<usize>,        // rpcs tried after the first one failed
+}
+
+impl RoutingRule {
+    pub fn matches(&self, method: &str) -> bool {
+        self.methods.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => pattern == method,
+        })
+    }
+
+    pub fn is_eligible(&self, rpc: &Rpc) -> bool {
+        self.tags.iter().all(|tag| rpc.tags.contains(tag))
+            && self.rpc_location.as_ref().is_none_or(|rpc_location| *rpc_location == rpc.rpc_location)
+    }
+}
+
+// Tags a client asks for with the X-Rpc-Tags header or the path segment after the chain id (i.e. /10/archive,~paid),
+// a ~ prefix makes a tag preferred instead of required
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RpcTags {
+    pub required: Vec<String>,  // only rpcs with all these tags are eligible
+    pub preferred: Vec<String>, // rpcs with all these tags are tried before the others
+}
+
+impl RpcTags {
+    pub fn parse(tags: &str) -> Self {
+        let mut rpc_tags = RpcTags::default();
+        for tag in tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+            match tag.strip_prefix('~') {
+                Some(preferred) => rpc_tags.preferred.push(preferred.to_string()),
+                None => rpc_tags.required.push(tag.to_string()),
+            }
+        }
+        rpc_tags
+    }
+
+    pub fn extend(&mut self, other: RpcTags) {
+        self.required.extend(other.required);
+        self.preferred.extend(other.preferred);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.required.is_empty() && self.preferred.is_empty()
+    }
+
+    pub fn is_eligible(&self, rpc: &Rpc) -> bool {
+        self.required.iter().all(|tag| rpc.tags.contains(tag))
+    }
+
+    pub fn is_preferred(&self, rpc: &Rpc) -> bool {
+        self.preferred.iter().all(|tag| rpc.tags.contains(tag))
+    }
+}
+
+impl fmt::Display for RpcTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let preferred = self.preferred.iter().map(|tag| format!("~{}", tag));
+        let tags: Vec<String> = self.required.iter().cloned().chain(preferred).collect();
+        write!(f, "{}", tags.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_exact_methods_and_prefixes() {
+        let routing_rule = RoutingRule {
+            methods: vec!["eth_getLogs".to_string(), "debug_*".to_string()],
+            ..RoutingRule::default()
+        };
+        assert!(routing_rule.matches("eth_getLogs"));
+        assert!(routing_rule.matches("debug_traceTransaction"));
+        assert!(!routing_rule.matches("eth_getLogsX"));
+        assert!(!routing_rule.matches("trace_block"));
+        assert!(!RoutingRule::default().matches("eth_getLogs"));
+    }
+
+    #[test]
+    fn parse_splits_required_and_preferred_tags() {
+        let rpc_tags = RpcTags::parse("archive, ~paid,,trace");
+        assert_eq!(rpc_tags.required, vec!["archive", "trace"]);
+        assert_eq!(rpc_tags.preferred, vec!["paid"]);
+        assert_eq!(rpc_tags.to_string(), "archive,trace,~paid");
+        assert!(RpcTags::parse("").is_empty());
+    }
+
+    #[test]
+    fn parsed_tags_select_the_rpcs() {
+        let rpc_tags = RpcTags::parse("archive,~paid");
+        let rpc = |tags: &[&str]| Rpc { tags: tags.iter().map(|tag| tag.to_string()).collect(), ..Rpc::default() };
+        assert!(rpc_tags.is_eligible(&rpc(&["archive"])));
+        assert!(!rpc_tags.is_preferred(&rpc(&["archive"])));
+        assert!(rpc_tags.is_preferred(&rpc(&["archive", "paid"])));
+        assert!(!rpc_tags.is_eligible(&rpc(&["paid"])));
+    }
+}
@@ -1,3 +1,5 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{Response, StatusCode};
 use serde_json::{json, Value};
 
 #[derive(Debug)]
@@ -17,12 +19,51 @@ impl JsonRpcErrorResponse {
     }
 
     pub fn to_json(&self) -> String {
+        self.to_value(&self.id).to_string()
+    }
+
+    // Echo the id of the request, a batch gets the error once per entry with the id of each entry
+    pub fn to_json_for(&self, request: &Value) -> String {
+        match request {
+            Value::Array(entries) if !entries.is_empty() => Value::Array(entries
+                .iter()
+                .map(|entry| self.to_value_for(entry))
+                .collect())
+                .to_string(),
+            _ => self.to_value_for(request).to_string(),
+        }
+    }
+
+    pub fn to_value_for(&self, entry: &Value) -> Value {
+        // Ids that are not a string or a number cannot be echoed
+        match entry.get("id") {
+            Some(id) if id.is_string() || id.is_number() => self.to_value(id),
+            _ => self.to_value(&self.id),
+        }
+    }
+
+    pub fn to_response(&self) -> Response<String> {
+        self.response(self.to_json())
+    }
+
+    pub fn to_response_for(&self, request: &Value) -> Response<String> {
+        self.response(self.to_json_for(request))
+    }
+
+    fn response(&self, body: String) -> Response<String> {
+        Response::builder()
+            .status(self.error.code.http_status())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap()
+    }
+
+    fn to_value(&self, id: &Value) -> Value {
         json!({
-            "id": &self.id,
+            "id": id,
             "jsonrpc": &self.jsonrpc,
-            "error": &self.error.format_error(),
+            "error": self.error.to_value(),
         })
-            .to_string()
     }
 }
 
@@ -32,41 +73,134 @@ impl From<ApplicationError> for JsonRpcErrorResponse {
     }
 }
 
-#[derive(Debug)]
+// JSON-RPC error codes that depend on the node answering, another node may succeed
+pub const RETRYABLE_ERROR_CODES: [i64; 3] = [
+    -32005, // limit exceeded
+    -32603, // internal error
+    429,    // rate limited
+];
+
+// Fragments of node specific JSON-RPC error messages, matched in lowercase
+pub const RETRYABLE_ERROR_MESSAGES: [&str; 14] = [
+    "header not found",
+    "missing trie node",
+    "unknown block",
+    "block not found",
+    "rate limit",
+    "too many requests",
+    "limit exceeded",
+    "capacity exceeded",
+    "request timeout",
+    "request timed out",
+    "context deadline exceeded",
+    "syncing",
+    "not synced",
+    "service unavailable",
+];
+
+// Fragments of JSON-RPC error messages that every node would return, never retried
+pub const DETERMINISTIC_ERROR_MESSAGES: [&str; 7] = [
+    "execution reverted",
+    "revert",
+    "invalid argument",
+    "invalid params",
+    "insufficient funds",
+    "nonce too low",
+    "already known",
+];
+
+// Balancer errors outside the standard JSON-RPC codes use -32090 to -32099,
+// clear of the -32000 to -32005 server errors returned by the nodes
+#[derive(Debug, Clone, PartialEq)]
 pub enum ErrorCode {
-    BadRequest = 400,
-    NotFound = 404,
-    InternalServerError = 500,
-    RequestTimeout = 408,
-    HandleConnectionError = 502,
-    UnknownError = 520,
+    ParseError,            // -32700, the body is not valid JSON
+    BadRequest,            // -32600, invalid request
+    PayloadTooLarge,       // -32600, the body exceeds max_request_size, or the limit of the node
+    MethodNotFound,        // -32601, unknown route
+    InternalServerError,   // -32603, internal error of the balancer
+    Unauthorized,          // -32090, missing or invalid admin token
+    Forbidden,             // -32091, admin API not allowed from the client address
+    NotFound,              // -32092, no RPC for the chain or with the id
+    TooManyRequests,       // -32093, the node is rate limiting
+    RequestTimeout,        // -32094, the node did not answer in time
+    HandleConnectionError, // -32095, the node is unreachable or its response unreadable
+    UpstreamError,         // -32096, the node answered with an http server error
+    NoRpcResponded,        // -32097, every node failed
+    NoRpcAvailable,        // -32098, the RPCs of the chain are all disabled
+    UnknownError,          // -32099
 }
 
 impl ErrorCode {
-    fn as_str(&self) -> &'static str {
+    pub fn json_rpc_code(&self) -> i64 {
+        match *self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::BadRequest | ErrorCode::PayloadTooLarge => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InternalServerError => -32603,
+            ErrorCode::Unauthorized => -32090,
+            ErrorCode::Forbidden => -32091,
+            ErrorCode::NotFound => -32092,
+            ErrorCode::TooManyRequests => -32093,
+            ErrorCode::RequestTimeout => -32094,
+            ErrorCode::HandleConnectionError => -32095,
+            ErrorCode::UpstreamError => -32096,
+            ErrorCode::NoRpcResponded => -32097,
+            ErrorCode::NoRpcAvailable => -32098,
+            ErrorCode::UnknownError => -32099,
+        }
+    }
+
+    pub fn http_status(&self) -> StatusCode {
         match *self {
-            ErrorCode::BadRequest => "400",
-            ErrorCode::NotFound => "404",
-            ErrorCode::InternalServerError => "500",
-            ErrorCode::RequestTimeout => "408",
-            ErrorCode::HandleConnectionError => "502",
-            ErrorCode::UnknownError => "520",
+            ErrorCode::ParseError | ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::MethodNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::HandleConnectionError
+            | ErrorCode::UpstreamError
+            | ErrorCode::NoRpcResponded
+            | ErrorCode::UnknownError => StatusCode::BAD_GATEWAY,
+            ErrorCode::NoRpcAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::RequestTimeout => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ApplicationError {
     pub code: ErrorCode,
     pub message: String,
+    pub data: Option<Value>, // upstream and diagnostic details
 }
 
 impl ApplicationError {
     pub fn new(code: ErrorCode, message: String) -> Self {
-        Self { code, message }
+        Self { code, message, data: None }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
     }
 
     pub fn format_error(&self) -> String {
-        format!("{}: {}", self.code.as_str(), self.message)
+        match &self.data {
+            Some(data) => format!("{}: {} {}", self.code.json_rpc_code(), self.message, data),
+            None => format!("{}: {}", self.code.json_rpc_code(), self.message),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut error = json!({
+            "code": self.code.json_rpc_code(),
+            "message": &self.message,
+        });
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        error
     }
 }
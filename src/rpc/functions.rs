@@ -1,94 +1,579 @@
-use crate::{rpc::{
+use crate::{admin::functions::{
+    admin_request,
+    is_admin_path,
+}, archive::{
+    functions::{
+        history_rank,
+        is_missing_state,
+        keeps_state,
+        prefer_history,
+        record_missing_state,
+        requested_block,
+        requested_depth,
+    },
+    types::{HistoryRank, RequestedBlock, StateDepth},
+}, cache::functions::forward_cached_rpc_request, config::types::{ChainSettings, Settings}, head::functions::local_head_response, rpc::{
     types::{
-        AddRpcRequest,
+        CircuitState,
+        EjectionPolicy,
         JsonRpcRequest,
-        JsonRpcResponse,
+        ResponseClass,
         Rpc,
-        RpcLocation,
         RpcRequest,
+        RpcSelection,
+        BatchRoute,
+        BatchRouteKey,
+        BLOCK_LAG_HEADER,
+        RPC_TAGS_HEADER,
+        SERVED_BY_HEADER,
+        UPSTREAM_LATENCY_HEADER,
     },
     errors::{
         ApplicationError,
         ErrorCode,
         JsonRpcErrorResponse,
+        DETERMINISTIC_ERROR_MESSAGES,
+        RETRYABLE_ERROR_CODES,
+        RETRYABLE_ERROR_MESSAGES,
+    },
+}, sort::{
+    types::{
+        Algo,
+        BlockLagPolicy,
+    },
+    functions::{
+        broadcast_targets,
+        chain_head,
+        exclude_ejected,
+        sort_rpc_list_by_algo,
+        split_by_block_lag,
+    },
+}, routing::{
+    functions::{
+        matching_rule,
+        prefer_tagged,
     },
-}, websocket::types::RpcWebSocket, sort::{
-    types::Algo,
-    functions::sort_rpc_list_by_algo,
+    types::{
+        RoutingRule,
+        RpcTags,
+    },
+}, metrics::functions::{
+    get_metrics,
+    record_latencies,
+    record_request,
+    request_method,
+    upstream_label,
 }, CLIENT};
 
-use std::io::Error;
-use http_body_util::BodyExt;
-use hyper::{Request, Response};
-use serde_json::Value;
+use std::net::SocketAddr;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
 use simd_json::serde::from_str;
 use std::str::from_utf8;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use log::{debug, error, info};
-use std::str::FromStr;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
 use futures_util::stream::FuturesUnordered;
+use tokio::sync::RwLock;
 use tokio_stream::StreamExt;
 
-pub fn parse_rpc_request(value: Value) -> Result<RpcRequest, serde_json::Error> {
-    if let Ok(req) = serde_json::from_value::<JsonRpcRequest>(value.clone()) {
-        Ok(RpcRequest::JsonRpc(req))
-    } else if let Ok(reqs) = serde_json::from_value::<Vec<JsonRpcRequest>>(value.clone()) {
-        Ok(RpcRequest::JsonRpcArray(reqs))
-    } else if let Ok(req) = serde_json::from_value::<AddRpcRequest>(value.clone()) {
-        Ok(RpcRequest::AddRpc(req))
-    } else if let Ok(reqs) = serde_json::from_value::<Vec<AddRpcRequest>>(value.clone()) {
-        Ok(RpcRequest::AddRpcArray(reqs))
-    } else {
-        Err(serde_json::Error::io(Error::new(std::io::ErrorKind::Other, "Unknown request type")))
+pub fn parse_rpc_request(value: &Value) -> Result<RpcRequest, ApplicationError> {
+    match value {
+        Value::Array(entries) if entries.is_empty() => Err(ApplicationError::new(
+            ErrorCode::BadRequest,
+            "empty batch".to_string(),
+        )),
+        Value::Array(entries) => Ok(RpcRequest::JsonRpcArray(entries.iter().map(JsonRpcRequest::from_value).collect())),
+        _ => JsonRpcRequest::from_value(value).map(RpcRequest::JsonRpc),
     }
 }
 
 pub async fn forward_json_rpc_request(
-    request: Request<hyper::body::Incoming>,
-    rpc_list: Arc<Mutex<Vec<Rpc>>>, stat_vec_size: usize, algo: Algo,
+    request: Request<hyper::body::Incoming>, remote_addr: SocketAddr,
+    rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>,
 ) -> Result<Response<String>, hyper::Error> {
+    let response = route_request(request, remote_addr, rpc_list, config).await?;
+    Ok(with_json_content_type(response))
+}
+
+async fn route_request(
+    request: Request<hyper::body::Incoming>, remote_addr: SocketAddr,
+    rpc_list: Arc<Mutex<Vec<Rpc>>>, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        return get_metrics(rpc_list);
+    }
+    if is_admin_path(request.uri().path()) {
+        // The admin API is only served here when it has no listener of its own
+        if config.read().await.admin_address.is_none() {
+            return admin_request(request, remote_addr, rpc_list, config).await;
+        }
+        let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+            ErrorCode::MethodNotFound,
+            "Admin API is served on the admin address".to_string(),
+        ));
+        error!("Error: {}", json_response.error.format_error().as_str());
+        return Ok(json_response.to_response());
+    }
+
     let chain_id = extract_chain_id(request.uri().path());
-    let json_value = incoming_to_value(request).await?;
+    let rpc_tags = extract_rpc_tags(&request);
+    let max_request_size = config.read().await.max_request_size;
+    let json_value = match read_json_body(request, max_request_size).await? {
+        Ok(json_value) => json_value,
+        Err(app_error) => {
+            let json_response = JsonRpcErrorResponse::from(app_error);
+            error!("Error: {}", json_response.error.format_error().as_str());
+            return Ok(json_response.to_response());
+        },
+    };
+
+    // Unknown chains are answered here, the chain ids in the metrics labels are the configured ones
+    if chain_id != 0 && !rpc_list.lock().unwrap().iter().any(|rpc| rpc.chain_id == chain_id) {
+        let json_response = no_rpc_error(&[], chain_id);
+        record_request(0, &request_method(&json_value), "none", "no_upstream");
+        error!("Error: {}", json_response.error.format_error().as_str());
+        return Ok(json_response.to_response_for(&json_value));
+    }
 
-    match parse_rpc_request(json_value.clone()) {
+    match parse_rpc_request(&json_value) {
         Ok(RpcRequest::JsonRpc(req)) => {
-            if req.method == "eth_sendRawTransaction" {
-                forward_raw_transaction(rpc_list, chain_id, json_value.clone()).await
+            let response = if req.method == "eth_sendRawTransaction" {
+                forward_raw_transaction(rpc_list, chain_id, json_value.clone(), &rpc_tags, config).await?
+            }
+            else if let Some(response_string) = local_head_response(&rpc_list, chain_id, &json_value, &config).await {
+                record_request(chain_id, &req.method, "local", "success");
+                served_by_response("local", response_string)
             }
             else{
-                forward_rpc_request(rpc_list, chain_id, json_value.clone(), algo).await
+                forward_cached_rpc_request(rpc_list, chain_id, json_value.clone(), &rpc_tags, config).await?
+            };
+            // Notifications are forwarded but never answered
+            match req.is_notification() {
+                true => Ok(no_content_response()),
+                false => Ok(response),
             }
         },
-        Ok(RpcRequest::JsonRpcArray(_reqs)) => {
-            forward_rpc_request(rpc_list, chain_id, json_value.clone(), algo).await
+        Ok(RpcRequest::JsonRpcArray(reqs)) => {
+            forward_batch_request(rpc_list, chain_id, json_value, reqs, &rpc_tags, config).await
         },
-        Ok(RpcRequest::AddRpc(req)) => {
-            add_rpc(rpc_list, req, stat_vec_size).await
+        Err(app_error) => {
+            let json_response = JsonRpcErrorResponse::from(app_error);
+            error!("Error: {}", json_response.error.format_error().as_str());
+            Ok(json_response.to_response_for(&json_value))
+        }
+    }
+}
+
+pub async fn forward_batch_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize, json_value: Value,
+                                   reqs: Vec<Result<JsonRpcRequest, ApplicationError>>, rpc_tags: &RpcTags,
+                                   config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    let entries = json_value.as_array().cloned().unwrap_or_default();
+
+    // Only the valid entries are forwarded, the invalid ones are answered with their own error
+    let valid_entries: Vec<Value> = entries
+        .iter()
+        .zip(&reqs)
+        .filter(|(_, req)| req.is_ok())
+        .map(|(entry, _)| entry.clone())
+        .collect();
+    let (upstream_responses, forwarded_response) = match valid_entries.is_empty() {
+        true => (Vec::new(), None),
+        false => {
+            let valid_batch = Value::Array(valid_entries.clone());
+            let response = forward_batch_chunks(rpc_list, chain_id, valid_batch, rpc_tags, config).await?;
+            let upstream_responses = match serde_json::from_str::<Value>(response.body()) {
+                Ok(Value::Array(upstream_responses)) => upstream_responses,
+                // A single error for the whole batch is given to every entry
+                Ok(error @ Value::Object(_)) => valid_entries
+                    .iter()
+                    .map(|entry| {
+                        let mut error = error.clone();
+                        error["id"] = entry.get("id").cloned().unwrap_or_default();
+                        error
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            (upstream_responses, Some(response))
         },
-        Ok(RpcRequest::AddRpcArray(req)) => {
-            let mut responses = Vec::new();
-            for rpc in req {
-                let response = add_rpc(rpc_list.clone(), rpc, stat_vec_size).await?;
-                responses.push(response.into_body());
-            }
-            Ok(Response::new(format!("[{}]", responses.join(","))))
+    };
+
+    // Reassemble the responses in the order of the batch, notifications get none
+    let mut upstream_responses = match_responses(&valid_entries, upstream_responses).into_iter();
+    let mut responses = Vec::new();
+    for (entry, req) in entries.iter().zip(reqs) {
+        let req = match req {
+            Ok(req) => req,
+            Err(app_error) => {
+                let json_response = JsonRpcErrorResponse::from(app_error);
+                error!("Error: {} in batch entry {}", json_response.error.format_error().as_str(), entry);
+                responses.push(json_response.to_value_for(entry));
+                continue;
+            },
+        };
+        let upstream_response = upstream_responses.next().flatten();
+        if req.is_notification() {
+            continue;
+        }
+        match upstream_response {
+            Some(upstream_response) => responses.push(upstream_response),
+            None => {
+                let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+                    ErrorCode::UpstreamError,
+                    "No response for this batch entry".to_string(),
+                ));
+                error!("Error: {} for batch entry {}", json_response.error.format_error().as_str(), entry);
+                responses.push(json_response.to_value_for(entry));
+            },
+        }
+    }
+
+    if responses.is_empty() {
+        return Ok(no_content_response());
+    }
+    let body = Value::Array(responses).to_string();
+    Ok(match forwarded_response {
+        // The status and diagnostic headers of the upstream call are kept
+        Some(forwarded_response) => {
+            let (parts, _) = forwarded_response.into_parts();
+            Response::from_parts(parts, body)
         },
-        Err(error) => {
-            let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
-                ErrorCode::BadRequest,
-                error.to_string(),
-            ));
-            error!("Error: {}", json_response.error.format_error().as_str());
-            Ok(Response::new(json_response.to_json()))
+        None => {
+            let mut response = Response::new(body);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            response
+        },
+    })
+}
+
+pub async fn forward_batch_chunks(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize, batch: Value,
+                                  rpc_tags: &RpcTags, config: Arc<RwLock<Settings>>,
+) -> Result<Response<String>, hyper::Error> {
+    let start_time = Instant::now();
+    let method = request_method(&batch);
+    let entries = batch.as_array().cloned().unwrap_or_default();
+
+    let (algo, broadcast_selection, ejection, chain_settings, max_batch_size, routing_rules) = {
+        let config_guard = config.read().await;
+        (
+            config_guard.algo.clone(),
+            config_guard.broadcast_selection.clone(),
+            config_guard.ejection.clone(),
+            config_guard.chains.get(&chain_id).cloned().unwrap_or_default(),
+            config_guard.max_batch_size,
+            config_guard.routing_rules.clone(),
+        )
+    };
+
+    let rpc_list_copy = {
+        let rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard.clone()
+    };
+
+    // Entries are routed by the routing rule of their method and by the RPCs keeping the state of their block,
+    // the entries sharing both share the same RPCs
+    let chain_head = chain_head(&rpc_list_copy
+        .iter()
+        .filter(|rpc| rpc.chain_id == chain_id && rpc.enabled)
+        .collect::<Vec<&Rpc>>());
+    let entry_blocks: Vec<Option<RequestedBlock>> = entries
+        .iter()
+        .map(|entry| requested_block(entry, &rpc_list_copy))
+        .collect();
+    let entry_depths: Vec<Option<StateDepth>> = entry_blocks
+        .iter()
+        .map(|block| requested_depth(chain_head, *block))
+        .collect();
+    let entry_keys: Vec<BatchRouteKey> = entries
+        .iter()
+        .zip(&entry_depths)
+        .map(|(entry, depth)| (
+            matching_rule(&routing_rules, &request_method(entry)),
+            rpc_list_copy.iter().map(|rpc| history_rank(rpc, *depth)).collect(),
+        ))
+        .collect();
+    let mut batch_routes: Vec<BatchRoute> = Vec::new();
+    let mut route_errors: Vec<(&BatchRouteKey, JsonRpcErrorResponse)> = Vec::new();
+    // Position of the route of every entry in batch_routes, or of its error in route_errors
+    let mut entry_routes: Vec<Result<usize, usize>> = Vec::new();
+    for (index, entry_key) in entry_keys.iter().enumerate() {
+        if let Some(position) = batch_routes.iter().position(|batch_route| batch_route.key == *entry_key) {
+            entry_routes.push(Ok(position));
+            continue;
+        }
+        if let Some(position) = route_errors.iter().position(|(error_key, _)| *error_key == entry_key) {
+            entry_routes.push(Err(position));
+            continue;
         }
+        let (routing_rule, _) = entry_key;
+        let rpc_selection = match select_rpc_lists(&rpc_list_copy, chain_id, &chain_settings, &method, *routing_rule,
+                                                   rpc_tags, entry_blocks[index]) {
+            Ok(rpc_selection) => rpc_selection,
+            // Without any RPC for the whole batch the error is the one of a single request
+            Err(json_response) if entry_keys.iter().all(|other_key| other_key == entry_key) => {
+                return Ok(json_response.to_response_for(&batch));
+            },
+            Err(json_response) => {
+                entry_routes.push(Err(route_errors.len()));
+                route_errors.push((entry_key, json_response));
+                continue;
+            },
+        };
+        let depth = entry_depths[index];
+
+        // Batches are not raced, with broadcast the chunks are spread over the RPCs ranked by broadcast_selection
+        let algo = match routing_rule.and_then(|routing_rule| routing_rule.algo.clone()).unwrap_or(algo.clone()) {
+            Algo::Broadcast => broadcast_selection.clone(),
+            algo => algo,
+        };
+        let mut sorted_rpc_list = prefer_history(prefer_tagged(sort_rpc_list_by_algo(algo.clone(), rpc_selection.rpc_list), rpc_tags), depth);
+        // The chunks are spread over the healthy RPCs, the preferred ones and the ones known to keep the block
+        // when there are some, the others only get the retries
+        let mut healthy_rpc_list: Vec<&Rpc> = sorted_rpc_list.iter().filter(|rpc| !rpc.is_deprioritized()).copied().collect();
+        if healthy_rpc_list.iter().any(|rpc| rpc_tags.is_preferred(rpc)) {
+            healthy_rpc_list.retain(|rpc| rpc_tags.is_preferred(rpc));
+        }
+        if healthy_rpc_list.iter().any(|rpc| history_rank(rpc, depth) == HistoryRank::Keeps) {
+            healthy_rpc_list.retain(|rpc| history_rank(rpc, depth) == HistoryRank::Keeps);
+        }
+        sorted_rpc_list.extend(prefer_history(prefer_tagged(sort_rpc_list_by_algo(algo, rpc_selection.lagging_rpc_list), rpc_tags), depth));
+        if healthy_rpc_list.is_empty() {
+            healthy_rpc_list = sorted_rpc_list.clone();
+        }
+        entry_routes.push(Ok(batch_routes.len()));
+        batch_routes.push(BatchRoute { key: entry_key.clone(), sorted_rpc_list, healthy_rpc_list });
     }
+    let batch_size = |rpc: &Rpc| rpc.max_batch_size.unwrap_or(max_batch_size).max(1);
+    let max_attempts = |route: usize| batch_routes[route]
+        .key
+        .0
+        .and_then(|routing_rule| routing_rule.max_retries)
+        .map_or(usize::MAX, |max_retries| max_retries + 1);
+
+    // The first chunks of every route are spread round robin over its healthy RPCs
+    let mut chunks: Vec<(usize, &Rpc, Vec<usize>)> = Vec::new();
+    for (route, batch_route) in batch_routes.iter().enumerate() {
+        let route_entries: Vec<usize> = (0..entries.len())
+            .filter(|index| matches!(entry_routes[*index], Ok(entry_route) if entry_route == route))
+            .collect();
+        let mut next_entry = 0;
+        let mut next_rpc = 0;
+        while next_entry < route_entries.len() {
+            let rpc = batch_route.healthy_rpc_list[next_rpc % batch_route.healthy_rpc_list.len()];
+            let end = (next_entry + batch_size(rpc)).min(route_entries.len());
+            chunks.push((route, rpc, route_entries[next_entry..end].to_vec()));
+            next_entry = end;
+            next_rpc += 1;
+        }
+    }
+    if chunks.len() > 1 {
+        info!("Batch of {} entries split in {} chunks over {:?}",
+            entries.len(),
+            chunks.len(),
+            chunks.iter().map(|(_, rpc, indexes)| (rpc_host(&rpc.url), indexes.len())).collect::<Vec<(&str, usize)>>(),
+        );
+    }
+
+    let mut responses: Vec<Option<Value>> = vec![None; entries.len()];
+    let mut answered = vec![false; entries.len()];
+    let mut tried: Vec<Vec<usize>> = vec![Vec::new(); entries.len()];
+    let mut missing_state = vec![false; entries.len()];
+    let mut served_by: Vec<&str> = Vec::new();
+    let mut max_srv_latency = 0;
+    let mut max_block_lag = 0;
+    while !chunks.is_empty() {
+        // The futures are polled in place (not spawned), like the broadcast ones
+        let intra_latency = start_time.elapsed().as_micros() as u64;
+        let mut futures = FuturesUnordered::new();
+        let mut retries: Vec<usize> = Vec::new();
+        for (route, rpc, indexes) in chunks {
+            // The trial of a half-open RPC is held by another request, the entries go to the next RPC
+            if !claim_rpc(&rpc_list, rpc, &ejection) {
+                for index in indexes {
+                    tried[index].push(rpc.id);
+                    retries.push(index);
+                }
+                continue;
+            }
+            let chunk = Value::Array(indexes.iter().map(|index| entries[*index].clone()).collect());
+            let timeout_ms = batch_routes[route].key.0.and_then(|routing_rule| routing_rule.timeout_ms);
+            debug!("Sending batch chunk {} to: {}", chunk, rpc.url);
+            futures.push(async move {
+                let response = send_request_with_timeout(rpc.url.clone(), chunk.clone(), timeout_ms).await;
+                (rpc, indexes, chunk, response)
+            });
+        }
+
+        while let Some((rpc, indexes, chunk, response)) = futures.next().await {
+            let total_latency = start_time.elapsed().as_micros() as u64;
+            let response_class = classify_response(&response);
+            record_request(chain_id, &method, &upstream_label(rpc), response_class.as_str());
+            record_rpc_outcome(&rpc_list, rpc, &response, &ejection);
+            // A transport or http error rejects the chunk as a whole, its entries are retried elsewhere
+            let mut chunk_failed = response.is_err();
+            // A 413, or a 400 to a chunk of several entries, is the limit of the node on the batch size
+            if let Err(app_error) = &response {
+                if indexes.len() > 1 && matches!(app_error.code, ErrorCode::PayloadTooLarge | ErrorCode::BadRequest) {
+                    halve_batch_size(&rpc_list, rpc, indexes.len());
+                }
+            }
+            if response.is_ok() {
+                record_rpc_latencies(&rpc_list, rpc, intra_latency, total_latency);
+            }
+
+            // Transport and http errors, or a single error object, are given to every entry of the chunk
+            let chunk_responses = match serde_json::from_str::<Value>(&response_body(response, &chunk)) {
+                Ok(Value::Array(chunk_responses)) => chunk_responses,
+                // Some nodes answer a batch of one entry with a bare response
+                Ok(response @ Value::Object(_)) if response.get("id").is_some_and(|id| !id.is_null()) => vec![response],
+                // An error without an id rejects the chunk as a whole, i.e. a batch too large for the node
+                Ok(error @ Value::Object(_)) => {
+                    chunk_failed = true;
+                    halve_batch_size(&rpc_list, rpc, indexes.len());
+                    chunk
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|entry| {
+                            let mut error = error.clone();
+                            error["id"] = entry.get("id").cloned().unwrap_or_default();
+                            error
+                        })
+                        .collect()
+                },
+                _ => Vec::new(),
+            };
+
+            let mut served = false;
+            let mut entry_responses = match_responses(chunk.as_array().unwrap(), chunk_responses).into_iter();
+            for index in indexes {
+                tried[index].push(rpc.id);
+                let entry_response = entry_responses.next().flatten();
+                // Notifications have no response to wait for
+                if entries[index].get("id").is_none() {
+                    continue;
+                }
+                match entry_response {
+                    Some(entry_response) if !chunk_failed
+                        && classify_response_entry(&entry_response) != ResponseClass::Retryable => {
+                        responses[index] = Some(entry_response);
+                        answered[index] = true;
+                        served = true;
+                    },
+                    // The last error is kept in case no other RPC answers the entry
+                    entry_response => {
+                        if entry_response.as_ref().is_some_and(is_missing_state) {
+                            record_missing_state(&rpc_list, rpc.id, entry_blocks[index]);
+                            missing_state[index] = true;
+                        }
+                        if entry_response.is_some() {
+                            responses[index] = entry_response;
+                        }
+                        retries.push(index);
+                    },
+                }
+            }
+            if served {
+                if !served_by.contains(&rpc_host(&rpc.url)) {
+                    served_by.push(rpc_host(&rpc.url));
+                }
+                max_srv_latency = max_srv_latency.max(total_latency - intra_latency);
+                max_block_lag = max_block_lag.max(chain_head.saturating_sub(rpc.last_block));
+            }
+        }
+
+        // Failed entries go to the best ranked RPC of their route they were not sent to yet,
+        // the entries a node missed the state of only to the RPCs known to keep it
+        chunks = Vec::new();
+        let mut retry_groups: Vec<(usize, &Rpc, Vec<usize>)> = Vec::new();
+        for index in retries {
+            let route = match entry_routes[index] {
+                Ok(route) if tried[index].len() < max_attempts(route) => route,
+                _ => continue,
+            };
+            let rpc = batch_routes[route]
+                .sorted_rpc_list
+                .iter()
+                .filter(|rpc| !missing_state[index] || keeps_state(rpc, entry_depths[index]))
+                .find(|rpc| !tried[index].contains(&rpc.id));
+            let rpc = match rpc {
+                Some(rpc) => *rpc,
+                None => continue,
+            };
+            match retry_groups.iter_mut().find(|(group_route, group_rpc, _)| *group_route == route && group_rpc.id == rpc.id) {
+                Some((_, _, indexes)) => indexes.push(index),
+                None => retry_groups.push((route, rpc, vec![index])),
+            }
+        }
+        for (route, rpc, indexes) in retry_groups {
+            warn!("Retrying {} batch entries on {}", indexes.len(), rpc_host(&rpc.url));
+            for chunk in indexes.chunks(batch_size(rpc)) {
+                chunks.push((route, rpc, chunk.to_vec()));
+            }
+        }
+    }
+
+    // Entries no RPC answered keep their last error, or get one
+    let mut batch_responses = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.get("id").is_none() {
+            continue;
+        }
+        match (responses[index].take(), &entry_routes[index]) {
+            (Some(response), _) => batch_responses.push(response),
+            (None, Err(position)) => batch_responses.push(route_errors[*position].1.to_value_for(entry)),
+            (None, Ok(_)) => {
+                let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+                    ErrorCode::NoRpcResponded,
+                    "No RPC nodes responded successfully".to_string(),
+                ));
+                error!("Error: {} for batch entry {}", json_response.error.format_error().as_str(), entry);
+                batch_responses.push(json_response.to_value_for(entry));
+            },
+        }
+    }
+
+    info!("Sent: Batch of {} entries, {} answered, served by {:?}",
+        entries.len(),
+        answered.iter().filter(|answered| **answered).count(),
+        served_by,
+    );
+    // The batch fails as a whole only when no entry got an answer
+    let status = match answered.iter().any(|answered| *answered) || batch_responses.is_empty() {
+        true => StatusCode::OK,
+        false => ErrorCode::NoRpcResponded.http_status(),
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json");
+    if !served_by.is_empty() {
+        response = response
+            .header(SERVED_BY_HEADER, served_by.join(","))
+            .header(UPSTREAM_LATENCY_HEADER, max_srv_latency)
+            .header(BLOCK_LAG_HEADER, max_block_lag);
+    }
+    Ok(response.body(Value::Array(batch_responses).to_string()).unwrap())
 }
 
 pub async fn forward_raw_transaction(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize,
-                                 json_value: Value,
+                                 json_value: Value, rpc_tags: &RpcTags, config: Arc<RwLock<Settings>>,
 ) -> Result<Response<String>, hyper::Error> {
 
+    // Raw transactions are sent to every eligible RPC, only the restrictions and the timeout of a routing rule
+    // and the required tags apply
+    let (ejection, routing_rule) = {
+        let config_guard = config.read().await;
+        (
+            config_guard.ejection.clone(),
+            matching_rule(&config_guard.routing_rules, "eth_sendRawTransaction").cloned(),
+        )
+    };
+    let timeout_ms = routing_rule.as_ref().and_then(|routing_rule| routing_rule.timeout_ms);
+
     let rpc_list_copy = {
         let rpc_guard = rpc_list.lock().unwrap();
         // Here only the operation that needs exclusive access to the data is performed.
@@ -97,15 +582,29 @@ pub async fn forward_raw_transaction(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: u
     };
 
     // Filter the RPCs by chain ID if chain_id is not 0
+    // Ejected RPCs are skipped unless all of them are ejected
     let filtered_rpc_list: Vec<Rpc> = {
-        let rpc_list_copy_clone: Vec<Rpc> = rpc_list_copy
+        let rpc_list_copy_clone: Vec<&Rpc> = rpc_list_copy
             .iter()
-            .filter(|rpc| rpc.chain_id == chain_id)
-            .cloned()
+            .filter(|rpc| rpc.chain_id == chain_id && rpc.enabled)
+            .filter(|rpc| routing_rule.as_ref().is_none_or(|routing_rule| routing_rule.is_eligible(rpc)))
+            .filter(|rpc| rpc_tags.is_eligible(rpc))
             .collect();
-        rpc_list_copy_clone.clone()
+        exclude_ejected(rpc_list_copy_clone)
+            .into_iter()
+            .filter(|rpc| claim_rpc(&rpc_list, rpc, &ejection))
+            .cloned()
+            .collect()
     };
 
+    if filtered_rpc_list.is_empty() {
+        let json_response = no_rpc_error(&rpc_list_copy, chain_id);
+        record_request(chain_id, "eth_sendRawTransaction", "none", "no_upstream");
+        error!("Error: {}", json_response.error.format_error().as_str());
+        return Ok(json_response.to_response_for(&json_value));
+    }
+    let chain_head = chain_head(&filtered_rpc_list.iter().collect::<Vec<&Rpc>>());
+
     let last_response = {
         let mut futures = FuturesUnordered::new();
         let mut results = Vec::new();
@@ -113,21 +612,27 @@ pub async fn forward_raw_transaction(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: u
             let json_value_clone = json_value.clone();
             info!("Sending raw transaction {} to: {}", json_value.clone(), rpc.url);
             futures.push(tokio::spawn(async move {
-                send_request(rpc.url.clone(), json_value_clone.clone())
+                let start_time = Instant::now();
+                let response = send_request_with_timeout(rpc.url.clone(), json_value_clone.clone(), timeout_ms).await;
+                (rpc.clone(), response, start_time.elapsed().as_micros() as u64)
             }));
         }
         while let Some(result) = futures.next().await {
             match result {
                 // check is response transformed to serde_json have result field
-                Ok(response) => {
-                    let response_string = response.await.unwrap();
-                    results.push(response_string.clone());
-                    let response_json: Value = serde_json::from_str(&response_string).unwrap();
-                    if response_json["result"].is_null() {
+                Ok((rpc, response, srv_latency)) => {
+                    let response_class = classify_response(&response);
+                    record_request(chain_id, "eth_sendRawTransaction", &upstream_label(&rpc), response_class.as_str());
+                    record_rpc_outcome(&rpc_list, &rpc, &response, &ejection);
+                    let status = response_status(&response);
+                    let response_string = response_body(response, &json_value);
+                    let response = upstream_response(status, response_string.clone(), &rpc, srv_latency, chain_head);
+                    if response_class != ResponseClass::Success {
+                        results.push(response);
                         continue;
                     }
                     info!("Sent: return correct response: {}", response_string);
-                    return Ok(Response::new(response_string.clone()));
+                    return Ok(response);
                 },
                 Err(e) => {
                     let json_rpc_error = JsonRpcErrorResponse::from(ApplicationError::new(
@@ -135,25 +640,49 @@ pub async fn forward_raw_transaction(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: u
                         e.to_string(),
                     ));
                     error!("Error: {}", json_rpc_error.error.format_error().as_str());
-                    results.push(json_rpc_error.to_json());
+                    results.push(json_rpc_error.to_response_for(&json_value));
                     continue;
                 },
             }
         }
 
-        info!("None of the RPC nodes responded successfully: {} ", results[0].clone());
-        Ok(Response::new(results[0].clone()))
+        // The first failure is returned
+        let response = results.swap_remove(0);
+        info!("None of the RPC nodes responded successfully: {} ", response.body());
+        Ok(response)
     };
 
     //Return last Response
-    return last_response;
+    last_response
 }
 
 pub async fn forward_rpc_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize,
-                                 json_value: Value, algo: Algo,
+                                 json_value: Value, rpc_tags: &RpcTags, config: Arc<RwLock<Settings>>,
 ) -> Result<Response<String>, hyper::Error> {
 
     let start_time = Instant::now();
+    let method = request_method(&json_value);
+
+    let (algo, broadcast_size, broadcast_selection, ejection, chain_settings, routing_rule) = {
+        let config_guard = config.read().await;
+        (
+            config_guard.algo.clone(),
+            config_guard.broadcast_size,
+            config_guard.broadcast_selection.clone(),
+            config_guard.ejection.clone(),
+            config_guard.chains.get(&chain_id).cloned().unwrap_or_default(),
+            matching_rule(&config_guard.routing_rules, &method).cloned(),
+        )
+    };
+    // The routing rule of the method replaces the algo, and bounds the timeout and the retries
+    let (algo, timeout_ms, max_attempts) = match &routing_rule {
+        Some(routing_rule) => (
+            routing_rule.algo.clone().unwrap_or(algo),
+            routing_rule.timeout_ms,
+            routing_rule.max_retries.map_or(usize::MAX, |max_retries| max_retries + 1),
+        ),
+        None => (algo, None, usize::MAX),
+    };
 
     let rpc_list_copy = {
         let rpc_guard = rpc_list.lock().unwrap();
@@ -161,103 +690,524 @@ pub async fn forward_rpc_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, chain_id: usize
         // In this case, a copy of the data is being made.
         rpc_guard.clone()
     };
+    let requested_block = requested_block(&json_value, &rpc_list_copy);
+
+    let RpcSelection { chain_head, rpc_list: filtered_rpc_list, lagging_rpc_list } =
+        match select_rpc_lists(&rpc_list_copy, chain_id, &chain_settings, &method, routing_rule.as_ref(), rpc_tags,
+                               requested_block) {
+            Ok(rpc_selection) => rpc_selection,
+            Err(json_response) => return Ok(json_response.to_response_for(&json_value)),
+        };
+    let depth = requested_depth(chain_head, requested_block);
+
+    if algo == Algo::Broadcast {
+        // The lagging RPCs are only raced when the routing restrictions left no other one,
+        // the preferred RPCs are raced alone when there are some
+        let mut candidates = match filtered_rpc_list.is_empty() {
+            true => lagging_rpc_list,
+            false => filtered_rpc_list,
+        };
+        if candidates.iter().any(|rpc| rpc_tags.is_preferred(rpc)) {
+            candidates.retain(|rpc| rpc_tags.is_preferred(rpc));
+        }
+        if candidates.iter().any(|rpc| history_rank(rpc, depth) == HistoryRank::Keeps) {
+            candidates.retain(|rpc| history_rank(rpc, depth) == HistoryRank::Keeps);
+        }
+        let targets = broadcast_targets(broadcast_selection, candidates, broadcast_size);
+        info!("broadcast targets: {:?}", targets.iter().map(|rpc| rpc.url.as_str()).collect::<Vec<&str>>());
+        return broadcast_rpc_request(rpc_list, targets, json_value, start_time, &ejection, chain_head, timeout_ms).await;
+    }
+
+    let mut sorted_rpc_list = prefer_history(prefer_tagged(sort_rpc_list_by_algo(algo.clone(), filtered_rpc_list), rpc_tags), depth);
+    sorted_rpc_list.extend(prefer_history(prefer_tagged(sort_rpc_list_by_algo(algo, lagging_rpc_list), rpc_tags), depth));
+    info!("sorted_rpc_list: {:?}", sorted_rpc_list.iter().map(|rpc| rpc.url.as_str()).collect::<Vec<&str>>());
+
+    // Loop through the sorted RPC list until an RPC answers with a result or a deterministic error
+    let mut last_error: Option<(&str, StatusCode, String)> = None;
+    let mut attempts = 0;
+    let mut archive_only = false;
+    for rpc in sorted_rpc_list {
+        // Once a node missed the state of the block, only the nodes known to keep it are worth a retry
+        if archive_only && !keeps_state(rpc, depth) {
+            continue;
+        }
+        if attempts == max_attempts {
+            break;
+        }
+        if !claim_rpc(&rpc_list, rpc, &ejection) {
+            continue;
+        }
+        attempts += 1;
+        debug!("Sending request {} to: {}", json_value, rpc.url);
+        let intra_latency = start_time.elapsed().as_micros() as u64;
+        let response = send_request_with_timeout(rpc.url.clone(), json_value.clone(), timeout_ms).await;
+        let total_latency = start_time.elapsed().as_micros() as u64;
+        let response_class = classify_response(&response);
+        record_request(chain_id, &method, &upstream_label(rpc), response_class.as_str());
+        record_rpc_outcome(&rpc_list, rpc, &response, &ejection);
+        let status = response_status(&response);
+        let response_string = response_body(response, &json_value);
+
+        if response_class == ResponseClass::Retryable {
+            if serde_json::from_str::<Value>(&response_string).is_ok_and(|response| is_missing_state(&response)) {
+                record_missing_state(&rpc_list, rpc.id, requested_block);
+                archive_only = true;
+            }
+            warn!("Retrying with the next RPC, {} failed: {}", rpc_host(&rpc.url), response_string);
+            last_error = Some((rpc_host(&rpc.url), status, response_string));
+            continue;
+        }
+
+        let avg_latency = record_rpc_latencies(&rpc_list, rpc, intra_latency, total_latency);
+        info!("Sent: Block Latency {} Intra Latency: {} Server Latency: {} Avg Server Latency: {:.0} RPC: {}",
+            chrono::Utc::now().timestamp_millis() as u64 - rpc.last_block_ts,
+            intra_latency,
+            total_latency,
+            avg_latency,
+            rpc_host(&rpc.url),
+        );
 
+        // info!("Block Latency: {} ms", chrono::Utc::now().timestamp_millis() as u64 - rpc.last_block_ts);
+        // info!("Intra_latency: {} μs.", intra_latency);
+        // info!("Srv_latency: {} μs.", total_latency - intra_latency);
+        // debug!("Total_latency: {} μs.", total_latency);
+        debug!("Response: {:?}", response_string);
+
+        return Ok(upstream_response(status, response_string, rpc, total_latency - intra_latency, chain_head));
+    }
+
+    // If no RPC answered after iterating over the entire list, return an error with the last failure
+    let mut app_error = ApplicationError::new(
+        ErrorCode::NoRpcResponded,
+        "No RPC nodes responded successfully".to_string(),
+    );
+    let mut last_status = None;
+    if let Some((upstream, status, response_string)) = last_error {
+        last_status = Some(status);
+        // Only the error object of a single response, batches and non JSON bodies are kept whole
+        let last_error = match serde_json::from_str::<Value>(&response_string) {
+            Ok(response) => response.get("error").cloned().unwrap_or(response),
+            Err(_) => Value::String(response_string),
+        };
+        app_error = app_error.with_data(json!({"upstream": upstream, "last_error": last_error}));
+    }
+    let json_response = JsonRpcErrorResponse::from(app_error);
+    error!("Error: {}", json_response.error.format_error().as_str());
+    let mut response = json_response.to_response_for(&json_value);
+    // A rate limit or a timeout of the last node is reported as such to the client
+    if let Some(status @ (StatusCode::TOO_MANY_REQUESTS | StatusCode::GATEWAY_TIMEOUT)) = last_status {
+        *response.status_mut() = status;
+    }
+    Ok(response)
+}
+
+pub fn select_rpc_lists<'a>(rpc_list_copy: &'a [Rpc], chain_id: usize, chain_settings: &ChainSettings,
+                            method: &str, routing_rule: Option<&RoutingRule>, rpc_tags: &RpcTags,
+                            requested_block: Option<RequestedBlock>,
+) -> Result<RpcSelection<'a>, JsonRpcErrorResponse> {
     // Filter the RPCs by chain ID if chain_id is not 0
     let filtered_rpc_list: Vec<&Rpc> = if chain_id != 0 {
         rpc_list_copy
             .iter()
-            .filter(|rpc| rpc.chain_id == chain_id)
+            .filter(|rpc| rpc.chain_id == chain_id && rpc.enabled)
             .collect()
     } else {
         let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
             ErrorCode::BadRequest,
             "chain_id path required (i.e. https://127.0.0.1:3000/10)".to_string(),
         ));
+        record_request(chain_id, method, "none", "bad_request");
         // log error with cause and url received
         error!("Error: {}", json_response.error.format_error().as_str());
-        return Ok(Response::new(json_response.to_json()));
+        return Err(json_response);
     };
 
     if filtered_rpc_list.is_empty() {
+        let json_response = no_rpc_error(rpc_list_copy, chain_id);
+        record_request(chain_id, method, "none", "no_upstream");
+        error!("Error: {}", json_response.error.format_error().as_str());
+        return Err(json_response);
+    }
+    let chain_head = chain_head(&filtered_rpc_list);
+
+    // RPCs too far behind the chain head are excluded, or only tried last when the policy allows
+    let (filtered_rpc_list, lagging_rpc_list) = match chain_settings.max_block_lag {
+        Some(max_block_lag) => {
+            let (chain_head, within_lag, lagging) = split_by_block_lag(filtered_rpc_list, max_block_lag);
+            if !lagging.is_empty() {
+                info!("Block lag: chain {} head {}, RPCs more than {} blocks behind {}: {:?}",
+                    chain_id,
+                    chain_head,
+                    max_block_lag,
+                    chain_settings.block_lag_policy.as_str(),
+                    lagging.iter().map(|rpc| (rpc_host(&rpc.url), chain_head - rpc.last_block)).collect::<Vec<(&str, u64)>>(),
+                );
+            }
+            match chain_settings.block_lag_policy {
+                BlockLagPolicy::Exclude => (within_lag, Vec::new()),
+                BlockLagPolicy::LastResort => (within_lag, lagging),
+            }
+        },
+        None => (filtered_rpc_list, Vec::new()),
+    };
+
+    // The routing rule of the method and the tags of the request restrict the eligible RPCs,
+    // the chain head is still the one of the chain
+    let mut rpc_selection = RpcSelection { chain_head, rpc_list: filtered_rpc_list, lagging_rpc_list };
+    if let Some(routing_rule) = routing_rule {
+        rpc_selection = restrict_rpc_selection(rpc_selection, |rpc| routing_rule.is_eligible(rpc));
+        if rpc_selection.is_empty() {
+            let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+                ErrorCode::NoRpcAvailable,
+                "No RPC nodes match the routing rule of the method".to_string(),
+            ).with_data(json!({"chain_id": chain_id, "methods": routing_rule.methods})));
+            record_request(chain_id, method, "none", "no_upstream");
+            error!("Error: {}", json_response.error.format_error().as_str());
+            return Err(json_response);
+        }
+    }
+    rpc_selection = restrict_rpc_selection(rpc_selection, |rpc| rpc_tags.is_eligible(rpc));
+    if rpc_selection.is_empty() {
         let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
-            ErrorCode::NotFound,
-            "No RPC nodes found for the specified chain ID".to_string(),
-        ));
+            ErrorCode::NoRpcAvailable,
+            "No RPC nodes have the requested tags".to_string(),
+        ).with_data(json!({"chain_id": chain_id, "tags": rpc_tags.to_string()})));
+        record_request(chain_id, method, "none", "no_upstream");
+        error!("Error: {}", json_response.error.format_error().as_str());
+        return Err(json_response);
+    }
+
+    // Deep history requests skip the RPCs known not to keep the state of the block
+    let depth = requested_depth(chain_head, requested_block);
+    rpc_selection = restrict_rpc_selection(rpc_selection, |rpc| history_rank(rpc, depth) != HistoryRank::Missing);
+    if rpc_selection.is_empty() {
+        let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+            ErrorCode::NoRpcAvailable,
+            "No RPC nodes keep the state of the requested block".to_string(),
+        ).with_data(json!({"chain_id": chain_id, "block": requested_block.and_then(RequestedBlock::number), "chain_head": chain_head})));
+        record_request(chain_id, method, "none", "no_upstream");
         error!("Error: {}", json_response.error.format_error().as_str());
-        return Ok(Response::new(json_response.to_json()));
+        return Err(json_response);
     }
+    Ok(rpc_selection)
+}
 
+pub fn restrict_rpc_selection<'a>(rpc_selection: RpcSelection<'a>, eligible: impl Fn(&Rpc) -> bool) -> RpcSelection<'a> {
+    RpcSelection {
+        chain_head: rpc_selection.chain_head,
+        rpc_list: rpc_selection.rpc_list.into_iter().filter(|rpc| eligible(rpc)).collect(),
+        lagging_rpc_list: rpc_selection.lagging_rpc_list.into_iter().filter(|rpc| eligible(rpc)).collect(),
+    }
+}
 
+pub async fn broadcast_rpc_request(rpc_list: Arc<Mutex<Vec<Rpc>>>, targets: Vec<&Rpc>,
+                                   json_value: Value, start_time: Instant, ejection: &EjectionPolicy,
+                                   chain_head: u64, timeout_ms: Option<u64>,
+) -> Result<Response<String>, hyper::Error> {
+    let method = request_method(&json_value);
+    let intra_latency = start_time.elapsed().as_micros() as u64;
 
-    let sorted_rpc_list = sort_rpc_list_by_algo(algo, filtered_rpc_list);
-    info!("sorted_rpc_list: {:?}", sorted_rpc_list);
+    // The futures are polled in place (not spawned), so dropping them cancels the slower requests
+    let mut futures = FuturesUnordered::new();
+    for rpc in targets {
+        if !claim_rpc(&rpc_list, rpc, ejection) {
+            continue;
+        }
+        let json_value_clone = json_value.clone();
+        debug!("Broadcasting request {} to: {}", json_value, rpc.url);
+        futures.push(async move {
+            (rpc, send_request_with_timeout(rpc.url.clone(), json_value_clone, timeout_ms).await)
+        });
+    }
 
-    // Loop through the sorted RPC list and make a request to each RPC until a successful response is received
-    for rpc in sorted_rpc_list {
-        debug!("Sending request {} to: {}", json_value, rpc.url);
-        let intra_latency = start_time.elapsed().as_micros() as u64;
-        let response = send_request(rpc.url.clone(), json_value.clone()).await;
+    let mut first_response: Option<Response<String>> = None;
+    while let Some((rpc, response)) = futures.next().await {
         let total_latency = start_time.elapsed().as_micros() as u64;
-        if response.is_ok() {
-            {
-                let mut rpc_guard = rpc_list.lock().unwrap();
-                let index = rpc_guard
-                    .iter()
-                    .position(|r| r.eq(rpc))
-                    .unwrap();
-                // Here only the operation that needs exclusive access to the data is performed.
-                // In this case, update the latencies and arrival timestamps for the chosen RPC.
-                rpc_guard[index].intra_latencies.push(intra_latency);
-                rpc_guard[index].srv_latencies.push(total_latency - intra_latency);
-                rpc_guard[index].arrivals_ts.push(chrono::Utc::now().timestamp_millis() as u64);
-
-                // debug all rpc_guard
-                debug!("rpc_guard: {:?}", rpc_guard);
-            };
+        let response_class = classify_response(&response);
+        record_request(rpc.chain_id, &method, &upstream_label(rpc), response_class.as_str());
+        record_rpc_outcome(&rpc_list, rpc, &response, ejection);
+        let status = response_status(&response);
+        let response_string = response_body(response, &json_value);
 
-            info!("Sent: Block Latency {} Intra Latency: {} Server Latency: {} RPC: {}",
-                chrono::Utc::now().timestamp_millis() as u64 - rpc.last_block_ts,
-                intra_latency,
-                total_latency,
-                rpc.url.split("/").collect::<Vec<&str>>()[2],
-            );
-
-            // info!("Block Latency: {} ms", chrono::Utc::now().timestamp_millis() as u64 - rpc.last_block_ts);
-            // info!("Intra_latency: {} μs.", intra_latency);
-            // info!("Srv_latency: {} μs.", total_latency - intra_latency);
-            // debug!("Total_latency: {} μs.", total_latency);
-            debug!("Response: {:?}", response);
-
-            let response_string = response.unwrap();
-            return Ok(Response::new(response_string));
+        // Only a result wins the race, errors are kept in case no target returns one
+        if response_class != ResponseClass::Success {
+            debug!("Discarding broadcast response from {}: {}", rpc.url, response_string);
+            first_response.get_or_insert_with(|| {
+                upstream_response(status, response_string, rpc, total_latency - intra_latency, chain_head)
+            });
+            continue;
         }
+
+        let avg_latency = record_rpc_latencies(&rpc_list, rpc, intra_latency, total_latency);
+        info!("Sent: Broadcast winner Block Latency {} Intra Latency: {} Server Latency: {} Avg Server Latency: {:.0} RPC: {}",
+            chrono::Utc::now().timestamp_millis() as u64 - rpc.last_block_ts,
+            intra_latency,
+            total_latency,
+            avg_latency,
+            rpc_host(&rpc.url),
+        );
+        return Ok(upstream_response(status, response_string, rpc, total_latency - intra_latency, chain_head));
     }
 
-    // If no successful response is received after iterating over the entire list, return an error
-    let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
-        ErrorCode::InternalServerError,
-        "No RPC nodes responded successfully".to_string(),
-    ));
-    error!("Error: {}", json_response.error.format_error().as_str());
-    Ok(Response::new(json_response.to_json()))
+    // None of the targets returned a result, forward the first error received
+    let response = match first_response {
+        Some(response) => response,
+        // Every target was a half-open RPC with a trial in flight
+        None => JsonRpcErrorResponse::from(ApplicationError::new(
+            ErrorCode::NoRpcResponded,
+            "No RPC nodes responded successfully".to_string(),
+        )).to_response_for(&json_value),
+    };
+    info!("None of the broadcast RPC nodes returned a result: {}", response.body());
+    Ok(response)
+}
+
+pub fn classify_response(response: &Result<String, ApplicationError>) -> ResponseClass {
+    let response_string = match response {
+        Ok(response_string) => response_string,
+        // Transport errors, 5xx and 429 depend on the node, other http errors on the request
+        Err(app_error) => return match app_error.code {
+            ErrorCode::BadRequest | ErrorCode::Unauthorized | ErrorCode::Forbidden | ErrorCode::NotFound => {
+                ResponseClass::Deterministic
+            },
+            _ => ResponseClass::Retryable,
+        },
+    };
+
+    match serde_json::from_str::<Value>(response_string) {
+        // For batches the whole batch is retried when any entry failed on the node
+        Ok(Value::Array(entries)) => entries
+            .iter()
+            .map(classify_response_entry)
+            .max_by_key(|response_class| match response_class {
+                ResponseClass::Success => 0,
+                ResponseClass::Deterministic => 1,
+                ResponseClass::Retryable => 2,
+            })
+            .unwrap_or(ResponseClass::Retryable),
+        Ok(entry) => classify_response_entry(&entry),
+        // Not JSON, i.e. an html error page from a proxy in front of the node
+        Err(_) => ResponseClass::Retryable,
+    }
+}
+
+pub fn is_node_failure(response: &Result<String, ApplicationError>) -> bool {
+    // Transport errors, timeouts, 5xx, 429 and bodies that are not JSON, i.e. an html error page
+    match response {
+        Ok(response_string) => serde_json::from_str::<Value>(response_string).is_err(),
+        // A request too large for the node is not a failure of the node
+        Err(app_error) => app_error.code != ErrorCode::PayloadTooLarge && classify_response(response) == ResponseClass::Retryable,
+    }
+}
+
+pub fn match_responses(entries: &[Value], mut responses: Vec<Value>) -> Vec<Option<Value>> {
+    // The response of every entry by id, in the order of the entries. Nodes may answer in any order,
+    // entries sharing an id take the responses with it in turn and notifications get none
+    entries
+        .iter()
+        .map(|entry| {
+            let id = entry.get("id")?;
+            let position = responses.iter().position(|response| response.get("id") == Some(id))?;
+            Some(responses.remove(position))
+        })
+        .collect()
+}
+
+pub fn classify_response_entry(entry: &Value) -> ResponseClass {
+    let error = match entry.get("error") {
+        Some(error) if !error.is_null() => error,
+        _ if entry.get("result").is_some() => return ResponseClass::Success,
+        _ => return ResponseClass::Retryable,
+    };
+
+    let message = error
+        .get("message")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if DETERMINISTIC_ERROR_MESSAGES.iter().any(|fragment| message.contains(fragment)) {
+        return ResponseClass::Deterministic;
+    }
+
+    let code = error.get("code").and_then(|c| c.as_i64()).unwrap_or_default();
+    if RETRYABLE_ERROR_CODES.contains(&code)
+        || RETRYABLE_ERROR_MESSAGES.iter().any(|fragment| message.contains(fragment)) {
+        return ResponseClass::Retryable;
+    }
+    ResponseClass::Deterministic
+}
+
+pub fn response_body(response: Result<String, ApplicationError>, request: &Value) -> String {
+    // Upstream failures are sent back to the client as JSON-RPC errors
+    match response {
+        Ok(response_string) => response_string,
+        Err(app_error) => JsonRpcErrorResponse::from(app_error).to_json_for(request),
+    }
+}
+
+pub fn response_status(response: &Result<String, ApplicationError>) -> StatusCode {
+    // Only the http errors of the node change the status, JSON-RPC errors are answered with 200
+    match response {
+        Ok(_) => StatusCode::OK,
+        Err(app_error) => app_error.code.http_status(),
+    }
+}
+
+pub fn upstream_response(status: StatusCode, response_string: String, rpc: &Rpc, srv_latency: u64,
+                         chain_head: u64) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(SERVED_BY_HEADER, rpc_host(&rpc.url))
+        .header(UPSTREAM_LATENCY_HEADER, srv_latency)
+        .header(BLOCK_LAG_HEADER, chain_head.saturating_sub(rpc.last_block))
+        .body(response_string)
+        .unwrap()
+}
+
+pub fn served_by_response(served_by: &str, response_string: String) -> Response<String> {
+    // Answered by the balancer itself, i.e. from the cache or the tracked heads
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .header(SERVED_BY_HEADER, served_by)
+        .body(response_string)
+        .unwrap()
+}
+
+pub fn no_content_response() -> Response<String> {
+    let mut response = Response::new(String::new());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    response
+}
+
+pub fn with_json_content_type(mut response: Response<String>) -> Response<String> {
+    if response.status() == StatusCode::NO_CONTENT {
+        return response;
+    }
+    response
+        .headers_mut()
+        .entry(CONTENT_TYPE)
+        .or_insert(HeaderValue::from_static("application/json"));
+    response
+}
+
+pub fn no_rpc_error(rpc_list: &[Rpc], chain_id: usize) -> JsonRpcErrorResponse {
+    // Unknown chains are not found, known chains with only disabled RPCs are unavailable
+    let app_error = match rpc_list.iter().any(|rpc| rpc.chain_id == chain_id) {
+        true => ApplicationError::new(
+            ErrorCode::NoRpcAvailable,
+            "No enabled RPC nodes for the specified chain ID".to_string(),
+        ),
+        false => ApplicationError::new(
+            ErrorCode::NotFound,
+            "No RPC nodes found for the specified chain ID".to_string(),
+        ),
+    };
+    JsonRpcErrorResponse::from(app_error.with_data(json!({"chain_id": chain_id})))
+}
+
+pub fn record_rpc_latencies(rpc_list: &Arc<Mutex<Vec<Rpc>>>, rpc: &Rpc, intra_latency: u64,
+                            total_latency: u64) -> f64 {
+    record_latencies(rpc.chain_id, &upstream_label(rpc), intra_latency, total_latency - intra_latency);
+
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    // Here only the operation that needs exclusive access to the data is performed.
+    // In this case, update the latencies and arrival timestamps for the chosen RPC.
+    let avg_latency = match rpc_guard.iter_mut().find(|r| r.id == rpc.id) {
+        Some(rpc_entry) => {
+            rpc_entry.record_latencies(intra_latency, total_latency - intra_latency);
+            rpc_entry.avg_latency
+        },
+        None => 0.0,
+    };
+
+    // debug all rpc_guard
+    debug!("rpc_guard: {:?}", rpc_guard);
+    avg_latency
+}
+
+pub fn halve_batch_size(rpc_list: &Arc<Mutex<Vec<Rpc>>>, rpc: &Rpc, chunk_size: usize) {
+    // The RPC rejected a chunk as a whole, the next chunks sent to it are half as large until the config is reloaded
+    let batch_size = (chunk_size / 2).max(1);
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    if let Some(rpc_entry) = rpc_guard.iter_mut().find(|r| r.id == rpc.id) {
+        if rpc_entry.max_batch_size.is_none_or(|max_batch_size| max_batch_size > batch_size) {
+            warn!("RPC {} rejected a batch of {} entries, sending it at most {}", rpc_host(&rpc.url), chunk_size, batch_size);
+            rpc_entry.max_batch_size = Some(batch_size);
+        }
+    }
 }
 
-pub async fn send_request(url: String, tx: Value) -> Result<String, hyper::Error> {
+pub fn claim_rpc(rpc_list: &Arc<Mutex<Vec<Rpc>>>, rpc: &Rpc, ejection: &EjectionPolicy) -> bool {
+    // A half-open RPC takes a single trial request, the others skip it until the trial is decided
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    match rpc_guard.iter_mut().find(|r| r.id == rpc.id) {
+        Some(rpc_entry) => rpc_entry.breaker.begin_request(ejection, now),
+        None => true,
+    }
+}
 
-    let response = match CLIENT.post(url).json(&tx).send().await {
+pub fn record_rpc_outcome(rpc_list: &Arc<Mutex<Vec<Rpc>>>, rpc: &Rpc, response: &Result<String, ApplicationError>,
+                          ejection: &EjectionPolicy) {
+    // Only the failures of the node count against the RPC, JSON-RPC errors depend on the request
+    let success = !is_node_failure(response);
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+
+    let mut rpc_guard = rpc_list.lock().unwrap();
+    let rpc_entry = match rpc_guard.iter_mut().find(|r| r.id == rpc.id) {
+        Some(rpc_entry) => rpc_entry,
+        None => return,
+    };
+    match rpc_entry.breaker.record(success, ejection, now) {
+        Some(CircuitState::Open) => warn!(
+            "Ejecting RPC {} for {} ms after {} consecutive failures, error rate {:.2}",
+            rpc_host(&rpc_entry.url),
+            ejection.cooldown_ms,
+            rpc_entry.breaker.consecutive_failures,
+            rpc_entry.breaker.error_rate(),
+        ),
+        Some(_) => info!("Re-admitting RPC {}", rpc_host(&rpc_entry.url)),
+        None => {},
+    }
+}
+
+pub async fn send_request(url: String, tx: Value) -> Result<String, ApplicationError> {
+    send_request_with_timeout(url, tx, None).await
+}
+
+pub async fn send_request_with_timeout(url: String, tx: Value, timeout_ms: Option<u64>,
+) -> Result<String, ApplicationError> {
+
+    let upstream = rpc_host(&url).to_string();
+    let mut request = CLIENT.post(&url).json(&tx);
+    if let Some(timeout_ms) = timeout_ms {
+        request = request.timeout(Duration::from_millis(timeout_ms));
+    }
+    let response = match request.send().await {
         Ok(response) => {
             if response.status().is_success() {
                 response
             } else {
                 let app_error = {
-                    if response.status().is_client_error() {
+                    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                        ApplicationError::new(
+                            ErrorCode::TooManyRequests,
+                            "RPC node rate limit exceeded".to_string(),
+                        )
+                    } else if response.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+                        ApplicationError::new(
+                            ErrorCode::PayloadTooLarge,
+                            "Request too large for the RPC node".to_string(),
+                        )
+                    } else if response.status().is_client_error() {
                         ApplicationError::new(
                             ErrorCode::BadRequest,
                             "Bad request".to_string(),
                         )
                     } else if response.status().is_server_error() {
                         ApplicationError::new(
-                            ErrorCode::InternalServerError,
+                            ErrorCode::UpstreamError,
                             "Internal server error".to_string(),
                         )
                     } else {
@@ -266,10 +1216,9 @@ pub async fn send_request(url: String, tx: Value) -> Result<String, hyper::Error
                             response.status().to_string(),
                         )
                     }
-                };
-                let error_response = JsonRpcErrorResponse::from(app_error);
-                error!("Error: {}", error_response.error.format_error().as_str());
-                return Ok(error_response.to_json());
+                }.with_data(json!({"upstream": upstream, "status": response.status().as_u16()}));
+                error!("Error: {}", app_error.format_error().as_str());
+                return Err(app_error);
             }
         },
         Err(error) => {
@@ -290,94 +1239,232 @@ pub async fn send_request(url: String, tx: Value) -> Result<String, hyper::Error
                         error.to_string(),
                     )
                 }
-            };
+            }.with_data(json!({"upstream": upstream}));
 
-            let error_response = JsonRpcErrorResponse::from(app_error);
-            error!("Error: {}", error_response.error.format_error().as_str());
-            return Ok(error_response.to_json());
+            error!("Error: {}", app_error.format_error().as_str());
+            return Err(app_error);
         }
     };
 
-    let response_text = response.text().await.unwrap();
-
-
-    Ok(response_text)
+    response.text().await.map_err(|error| {
+        let app_error = ApplicationError::new(
+            ErrorCode::HandleConnectionError,
+            format!("Error reading RPC node response: {}", error),
+        ).with_data(json!({"upstream": upstream}));
+        error!("Error: {}", app_error.format_error().as_str());
+        app_error
+    })
 }
 
-pub async fn add_rpc(rpc_list: Arc<Mutex<Vec<Rpc>>>, add_rpc_request: AddRpcRequest,
-                     stat_vec_size: usize) -> Result<Response<String>, hyper::Error> {
-    let already_added=
-        {
-            let rpc_guard = rpc_list.lock().unwrap();
-            rpc_guard.iter().any(|rpc| rpc.url == add_rpc_request.url)
-        };
-    if already_added {
-        let response = JsonRpcResponse::from("Rpc already added".to_string());
-        info!("{}: {}", response.result, add_rpc_request.url);
-        return Ok(Response::new(response.to_json()));
-    }
-    let add_rpc_request_clone = add_rpc_request.clone();
-
-    let rpc = Rpc::new(add_rpc_request.url,
-                       add_rpc_request.ws_url,
-                       add_rpc_request.chain_id,
-                       RpcLocation::from_str(add_rpc_request.rpc_location.as_str()).unwrap(),
-                       stat_vec_size).await;
-    let rpc_clone =
-        {
-            let mut rpc_guard = rpc_list.lock().unwrap();
-            let rpc_result = rpc.clone();
-            rpc_guard.push(rpc);
-            debug!("Rpc_guard after add_rpc : {:?}", rpc_guard);
-            rpc_result
-        };
-    let len = {
-        let rpc_guard = rpc_list.lock().unwrap();
-        rpc_guard.len()
-    };
-    let rpc_list_clone = rpc_list.clone();
-    let rpc_websocket =
-        RpcWebSocket::new(rpc_clone.ws_url.clone());
-    let mut rpc_websocket_clone = rpc_websocket.await.clone();
-    tokio::task::spawn(async move {
-        rpc_websocket_clone.start_rpc(rpc_list_clone, len - 1)
-            .await;
-    });
-
-    let response = JsonRpcResponse::from("Rpc added successfully".to_string());
-    info!("{}: {}", response.result, add_rpc_request_clone.url);
-    Ok(Response::new(response.to_json()))
+pub fn rpc_host(url: &str) -> &str {
+    // Host part of the rpc url, keeps api keys in the path out of logs and metrics
+    url.split('/').nth(2).unwrap_or(url)
 }
 
 pub fn extract_chain_id(path: &str) -> usize {
-    // Extract the chain ID from the request path
+    // Extract the chain ID from the request path, the last segment or the one before the tags (i.e. /10/archive)
     let segments: Vec<&str> = path.split('/').collect();
     let chain_id = segments
-        .last()
-        .unwrap_or(&"0")
-        .parse::<usize>()
+        .iter()
+        .rev()
+        .take(2)
+        .find_map(|segment| segment.parse::<usize>().ok())
         .unwrap_or(0);
     debug!("chain_id: {}", chain_id);
     chain_id
 }
 
+pub fn extract_rpc_tags<B>(request: &Request<B>) -> RpcTags {
+    // Tags of the X-Rpc-Tags header and of the path segment after the chain id
+    let mut rpc_tags = request
+        .headers()
+        .get_all(RPC_TAGS_HEADER)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .map(RpcTags::parse)
+        .fold(RpcTags::default(), |mut rpc_tags, header_tags| {
+            rpc_tags.extend(header_tags);
+            rpc_tags
+        });
+    let segments: Vec<&str> = request.uri().path().split('/').collect();
+    if let [.., chain_id, tags] = segments.as_slice() {
+        if chain_id.parse::<usize>().is_ok() && tags.parse::<usize>().is_err() {
+            rpc_tags.extend(RpcTags::parse(tags));
+        }
+    }
+    if !rpc_tags.is_empty() {
+        debug!("rpc_tags: {}", rpc_tags);
+    }
+    rpc_tags
+}
+
 pub async fn incoming_to_value(
     request: Request<hyper::body::Incoming>,
 ) -> Result<Value, hyper::Error> {
-    let tx = request.collect().await?.to_bytes().clone();
-    let mut tx = from_utf8(&tx).unwrap().to_owned();
+    // Invalid bodies are turned into a JSON string of the error, rejected by the deserialization of the caller
+    Ok(match read_json_body(request, usize::MAX).await? {
+        Ok(value) => value,
+        Err(app_error) => {
+            let error_response = JsonRpcErrorResponse::from(app_error);
+            error!("Error: {}", error_response.error.format_error().as_str());
+            Value::String(error_response.to_json())
+        },
+    })
+}
 
-    let ret = match unsafe { from_str(&mut tx) } {
-        Ok(ret) => ret,
-        Err(_) => {
-            let error_response = JsonRpcErrorResponse::from(ApplicationError::new(
+pub async fn read_json_body(
+    request: Request<hyper::body::Incoming>, max_request_size: usize,
+) -> Result<Result<Value, ApplicationError>, hyper::Error> {
+    let tx = match Limited::new(request.into_body(), max_request_size).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(error) => match error.downcast::<hyper::Error>() {
+            Ok(error) => return Err(*error),
+            Err(error) if error.is::<LengthLimitError>() => return Ok(Err(ApplicationError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Request body larger than {} bytes", max_request_size),
+            ))),
+            Err(error) => return Ok(Err(ApplicationError::new(
                 ErrorCode::BadRequest,
-                "Invalid Json".to_string(),
-            ));
-            error!("Error: {}", error_response.error.format_error().as_str());
-            return Ok(Value::String(error_response.to_json()));
-        }
+                error.to_string(),
+            ))),
+        },
+    };
+
+    let mut tx = match from_utf8(&tx) {
+        Ok(tx) => tx.to_owned(),
+        Err(_) => return Ok(Err(ApplicationError::new(
+            ErrorCode::ParseError,
+            "Invalid Json".to_string(),
+        ))),
     };
+    match unsafe { from_str(&mut tx) } {
+        Ok(ret) => Ok(Ok(ret)),
+        Err(_) => Ok(Err(ApplicationError::new(
+            ErrorCode::ParseError,
+            "Invalid Json".to_string(),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    fn result(id: Value, result: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "result": result})
+    }
+
+    fn error(code: i64, message: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "error": {"code": code, "message": message}})
+    }
+
+    #[test]
+    fn classify_response_entry_tells_node_failures_from_request_errors() {
+        assert_eq!(classify_response_entry(&result(json!(1), "0x1")), ResponseClass::Success);
+        // A null result is still an answer, i.e. an unknown transaction
+        assert_eq!(classify_response_entry(&json!({"jsonrpc": "2.0", "id": 1, "result": null})), ResponseClass::Success);
+        assert_eq!(classify_response_entry(&json!({"jsonrpc": "2.0", "id": 1})), ResponseClass::Retryable);
+        assert_eq!(classify_response_entry(&error(3, "execution reverted")), ResponseClass::Deterministic);
+        assert_eq!(classify_response_entry(&error(-32602, "Invalid params")), ResponseClass::Deterministic);
+        assert_eq!(classify_response_entry(&error(-32603, "internal error")), ResponseClass::Retryable);
+        assert_eq!(classify_response_entry(&error(-32000, "missing trie node abc")), ResponseClass::Retryable);
+        assert_eq!(classify_response_entry(&error(-32000, "Rate limit reached")), ResponseClass::Retryable);
+        assert_eq!(classify_response_entry(&error(-32000, "unexpected error")), ResponseClass::Deterministic);
+        assert_eq!(classify_response_entry(&error(-32601, "the method eth_foo does not exist")), ResponseClass::Deterministic);
+        assert_eq!(classify_response_entry(&error(-32000, "request timed out")), ResponseClass::Retryable);
+        // A timeout of the call itself happens on every node
+        assert_eq!(classify_response_entry(&error(-32000, "execution aborted (timeout = 5s)")), ResponseClass::Deterministic);
+    }
 
-    Ok(ret)
-}
\ No newline at end of file
+    #[test]
+    fn classify_response_entry_prefers_the_deterministic_message() {
+        // Some nodes report reverts with the internal error code
+        assert_eq!(classify_response_entry(&error(-32603, "execution reverted: paused")), ResponseClass::Deterministic);
+    }
+
+    fn entry(id: Option<Value>) -> Value {
+        let mut entry = json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": []});
+        if let Some(id) = id {
+            entry["id"] = id;
+        }
+        entry
+    }
+
+    #[test]
+    fn match_responses_follows_the_order_of_the_entries() {
+        let entries = vec![entry(Some(json!(1))), entry(Some(json!("b"))), entry(Some(json!(3)))];
+        let responses = vec![result(json!(3), "0x3"), result(json!(1), "0x1"), result(json!("b"), "0x2")];
+        assert_eq!(match_responses(&entries, responses), vec![
+            Some(result(json!(1), "0x1")),
+            Some(result(json!("b"), "0x2")),
+            Some(result(json!(3), "0x3")),
+        ]);
+    }
+
+    #[test]
+    fn match_responses_gives_duplicated_ids_a_response_each() {
+        let entries = vec![entry(Some(json!(1))), entry(Some(json!(1))), entry(Some(json!(1)))];
+        let responses = vec![result(json!(1), "0xa"), result(json!(1), "0xb")];
+        assert_eq!(match_responses(&entries, responses), vec![
+            Some(result(json!(1), "0xa")),
+            Some(result(json!(1), "0xb")),
+            None,
+        ]);
+    }
+
+    #[test]
+    fn match_responses_skips_notifications_and_keeps_null_ids() {
+        let entries = vec![entry(None), entry(Some(Value::Null)), entry(Some(json!(2)))];
+        let responses = vec![result(json!(2), "0x2"), result(Value::Null, "0x0")];
+        assert_eq!(match_responses(&entries, responses), vec![
+            None,
+            Some(result(Value::Null, "0x0")),
+            Some(result(json!(2), "0x2")),
+        ]);
+    }
+
+    async fn mock_rpc(name: &'static str, max_batch_size: usize) -> String {
+        // Answers every entry with its name, larger batches get a 413 like a node behind a proxy with a body limit
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(move |request: Request<hyper::body::Incoming>| async move {
+                    let body = request.into_body().collect().await?.to_bytes();
+                    let entries = serde_json::from_slice::<Value>(&body).unwrap().as_array().cloned().unwrap_or_default();
+                    let response = match entries.len() > max_batch_size {
+                        true => Response::builder().status(StatusCode::PAYLOAD_TOO_LARGE).body("Request Entity Too Large".to_string()).unwrap(),
+                        false => Response::new(Value::Array(entries.iter().map(|entry| result(entry["id"].clone(), name)).collect()).to_string()),
+                    };
+                    Ok::<_, hyper::Error>(response)
+                })));
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn forward_batch_chunks_retries_a_chunk_rejected_with_413_and_halves_the_batch_size() {
+        let small = Rpc { id: 1, url: mock_rpc("small", 2).await, chain_id: 10, last_block: 101, poll_ok: true, ..Rpc::default() };
+        let large = Rpc { id: 2, url: mock_rpc("large", 100).await, chain_id: 10, last_block: 100, poll_ok: true, ..Rpc::default() };
+        let rpc_list = Arc::new(Mutex::new(vec![small, large]));
+        let config = Arc::new(RwLock::new(Settings::default()));
+        let batch = Value::Array((1..=4).map(|id| entry(Some(json!(id)))).collect());
+
+        // The whole batch goes to the rpc with the highest head first
+        let response = forward_batch_chunks(rpc_list.clone(), 10, batch, &RpcTags::default(), config).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected: Vec<Value> = (1..=4).map(|id| result(json!(id), "large")).collect();
+        assert_eq!(serde_json::from_str::<Value>(response.body()).unwrap(), Value::Array(expected));
+        let rpc_guard = rpc_list.lock().unwrap();
+        assert_eq!(rpc_guard[0].max_batch_size, Some(2));
+        // A 413 is not a failure of the node
+        assert_eq!(rpc_guard[0].breaker.consecutive_failures, 0);
+    }
+}
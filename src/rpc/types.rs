@@ -1,34 +1,185 @@
+use crate::archive::functions::history_of;
+use crate::archive::types::{HistoryDepth, HistoryRank, LearnedHistory};
+use crate::head::types::{BlockHeader, BLOCK_HISTORY_SIZE};
+use crate::routing::types::RoutingRule;
+use crate::rpc::errors::{ApplicationError, ErrorCode};
 use std::collections::VecDeque;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Source of the stable rpc ids, 0 is never assigned
+static NEXT_RPC_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Diagnostic headers of the responses, the upstream host (or cache, local), its latency in μs and its blocks behind the chain head
+pub const SERVED_BY_HEADER: &str = "x-served-by";
+pub const UPSTREAM_LATENCY_HEADER: &str = "x-upstream-latency-us";
+pub const BLOCK_LAG_HEADER: &str = "x-block-lag";
+// Request header with the tags the upstream must have, or preferably have with a ~ prefix (i.e. archive,~paid)
+pub const RPC_TAGS_HEADER: &str = "x-rpc-tags";
+// Largest chunk of a batch sent to a single rpc when neither the config nor the rpc sets one
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Serialize, Debug, Clone)]
 pub struct JsonRpcRequest {
     pub jsonrpc: String,
     pub method: String,
-    pub params: Vec<Value>,
-    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>, // an array or an object, absent for methods without params
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,     // absent for notifications, a null id is still a request
 }
 
+impl JsonRpcRequest {
+    pub fn from_value(value: &Value) -> Result<Self, ApplicationError> {
+        let invalid_request = |message: &str| ApplicationError::new(ErrorCode::BadRequest, message.to_string());
+        let request = value.as_object().ok_or_else(|| invalid_request("expected a JSON-RPC request object"))?;
+
+        if request.get("jsonrpc").and_then(|jsonrpc| jsonrpc.as_str()) != Some("2.0") {
+            return Err(invalid_request("jsonrpc must be \"2.0\""));
+        }
+        let method = request
+            .get("method")
+            .and_then(|method| method.as_str())
+            .ok_or_else(|| invalid_request("method must be a string"))?;
+        let params = match request.get("params") {
+            None => None,
+            Some(params) if params.is_array() || params.is_object() => Some(params.clone()),
+            Some(_) => return Err(invalid_request("params must be an array or an object")),
+        };
+        let id = match request.get("id") {
+            None => None,
+            Some(id) if id.is_string() || id.is_number() || id.is_null() => Some(id.clone()),
+            Some(_) => return Err(invalid_request("id must be a string, a number or null")),
+        };
+
+        Ok(Self {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+            id,
+        })
+    }
+
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+}
+
+
+// Enabled rpcs of a chain that can serve a request, the lagging ones only when the block lag policy allows it
+pub struct RpcSelection<'a> {
+    pub chain_head: u64,
+    pub rpc_list: Vec<&'a Rpc>,
+    pub lagging_rpc_list: Vec<&'a Rpc>,
+}
+
+impl RpcSelection<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.rpc_list.is_empty() && self.lagging_rpc_list.is_empty()
+    }
+}
+
+// Routing rule of a batch entry and the history ranks of the RPCs for its block
+pub type BatchRouteKey<'a> = (Option<&'a RoutingRule>, Vec<HistoryRank>);
+
+// RPCs of the batch entries sharing a route key, ranked for the retries and healthy for the first chunks
+pub struct BatchRoute<'a> {
+    pub key: BatchRouteKey<'a>,
+    pub sorted_rpc_list: Vec<&'a Rpc>,
+    pub healthy_rpc_list: Vec<&'a Rpc>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddRpcRequest {
     pub url: String,
-    pub ws_url: String,
+    #[serde(default)]
+    pub ws_url: Option<String>,
     pub chain_id: usize,
     pub rpc_location: String,
+    #[serde(default)]
+    pub head_source: Option<String>,
+    #[serde(default)]
+    pub max_batch_size: Option<usize>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UpdateRpcRequest {
+    pub enabled: Option<bool>,
+    pub rpc_location: Option<String>,
+    pub chain_id: Option<usize>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RpcInfo {
+    pub id: usize,
+    pub url: String,
+    pub ws_url: Option<String>,
+    pub chain_id: usize,
+    pub rpc_location: String,
+    pub enabled: bool,
+    pub head_source: String,
+    pub ws_connected: bool,
+    pub head_stale: bool,
+    pub last_block: u64,
+    pub circuit_state: String,
+    pub max_batch_size: Option<usize>,
+    pub tags: Vec<String>,
+    pub history_depth: String,
+}
+
+impl From<&Rpc> for RpcInfo {
+    fn from(rpc: &Rpc) -> Self {
+        Self {
+            id: rpc.id,
+            url: rpc.url.clone(),
+            ws_url: rpc.ws_url.clone(),
+            chain_id: rpc.chain_id,
+            rpc_location: format!("{:?}", rpc.rpc_location),
+            enabled: rpc.enabled,
+            head_source: rpc.head_source.as_str().to_string(),
+            ws_connected: rpc.ws_connected,
+            head_stale: rpc.head_is_stale(),
+            last_block: rpc.last_block,
+            circuit_state: rpc.breaker.state(chrono::Utc::now().timestamp_millis() as u64).as_str().to_string(),
+            max_batch_size: rpc.max_batch_size,
+            tags: rpc.tags.clone(),
+            history_depth: history_of(rpc).to_string(),
+        }
+    }
 }
 
 pub enum RpcRequest {
     JsonRpc(JsonRpcRequest),
-    AddRpc(AddRpcRequest),
-    JsonRpcArray(Vec<JsonRpcRequest>),
-    AddRpcArray(Vec<AddRpcRequest>),
+    JsonRpcArray(Vec<Result<JsonRpcRequest, ApplicationError>>), // every entry of a batch is validated on its own
 }
 
 #[derive(Debug, Clone, PartialEq)]
+pub enum ResponseClass {
+    Success,       // the upstream returned a result
+    Deterministic, // an error any node would return, sent back to the client
+    Retryable,     // a node specific failure, the next rpc is tried
+}
+
+impl ResponseClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseClass::Success => "success",
+            ResponseClass::Deterministic => "error",
+            ResponseClass::Retryable => "retry",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum RpcLocation {
+    #[default]
     Local,
     External,
 }
@@ -46,6 +197,33 @@ impl FromStr for RpcLocation {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeadSource {
+    Ws,   // newHeads subscription, polling only takes over while the websocket is down
+    Poll, // eth_getBlockByNumber("latest") polling
+}
+
+impl HeadSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeadSource::Ws => "ws",
+            HeadSource::Poll => "poll",
+        }
+    }
+}
+
+impl FromStr for HeadSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ws" => Ok(HeadSource::Ws),
+            "poll" => Ok(HeadSource::Poll),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct JsonRpcResponse {
     pub id: Value,
@@ -55,8 +233,9 @@ pub struct JsonRpcResponse {
 
 impl JsonRpcResponse {
     pub fn new(result: String) -> Self {
+        // Admin requests carry no JSON-RPC id to echo
         Self {
-            id: Value::from(1),
+            id: Value::Null,
             jsonrpc: "2.0".to_string(),
             result,
         }
@@ -78,26 +257,37 @@ impl From<String> for JsonRpcResponse {
     }
 }
 
-
-impl Default for RpcLocation {
-    fn default() -> Self {
-        RpcLocation::Local
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct Rpc {
+    pub id: usize,                 // stable id of the rpc, survives removals of other rpcs
     pub url: String,               // url of the rpc
-    pub ws_url: String,            // url of the websocket to keep track of the latest block
+    pub ws_url: Option<String>,    // url of the websocket to keep track of the latest block
+    pub head_source: HeadSource,   // how the latest block is tracked
     pub chain_id: usize,           // id for chain_id, ethereum = 1, optimism = 10, base = 8453
     pub rpc_location: RpcLocation, // location of the rpc, local or external
+    pub enabled: bool,             // disabled rpcs keep tracking heads but are not routed to
     pub last_block: u64,           // blockchain last block number
     pub last_block_ts: u64,        // timestamp of the last block
     pub current_ts: u64,           // Arrival last block to calculate the latency
+    pub ws_connected: bool,        // websocket head subscription is alive
+    pub ws_disconnected_ts: u64,   // when the websocket went down, to fall back to polling
+    pub poll_ok: bool,             // the last head poll succeeded
+    pub head_overdue: bool,        // no head arrived within the expected block time of the chain
+    pub recent_blocks: VecDeque<BlockHeader>, // latest blocks reported by the rpc, oldest first
+    pub reorgs: u64,               // number of reorgs reported by the rpc
+    pub last_reorg_depth: u64,
+    pub minority_fork: bool,       // the rpc disagrees with the majority of its chain on a block hash
     pub avg_latency: f64,          // average latency of the rpc
     pub intra_latencies: LimitedVecDeque, // n last intra latencies of the rpc
     pub srv_latencies: LimitedVecDeque,   // n last srv latencies of the rpc
     pub arrivals_ts: LimitedVecDeque,
+    pub ws_shutdown: Arc<Notify>,  // stops the websocket listener when the rpc is removed
+    pub breaker: CircuitBreaker,   // passive health of the rpc from the requests sent to it
+    pub max_batch_size: Option<usize>, // largest batch the rpc accepts, the global max_batch_size when none
+    pub tags: Vec<String>,         // free-form classifiers matched by the routing rules, i.e. archive
+    pub history_depth: HistoryDepth, // how far back the rpc keeps the state, configured or probed
+    pub learned_history: Option<LearnedHistory>, // fewer blocks learned from a missing state error
+    pub admin_override: bool,      // added or updated through the admin api without persist, kept over reloads
 }
 
 impl PartialEq for Rpc {
@@ -109,17 +299,35 @@ impl PartialEq for Rpc {
 impl Default for Rpc {
     fn default() -> Self {
         Self {
+            id: 0,
             url: "".to_string(),
-            ws_url: "".to_string(),
+            ws_url: None,
+            head_source: HeadSource::Poll,
             chain_id: 0,
             rpc_location: RpcLocation::Local,
+            enabled: true,
             last_block: 0,
             last_block_ts: 0,
             current_ts: 0,
+            ws_connected: false,
+            ws_disconnected_ts: 0,
+            poll_ok: false,
+            head_overdue: false,
+            recent_blocks: VecDeque::new(),
+            reorgs: 0,
+            last_reorg_depth: 0,
+            minority_fork: false,
             avg_latency: 0.0,
             intra_latencies: LimitedVecDeque::new(1000),
             srv_latencies: LimitedVecDeque::new(1000),
             arrivals_ts: LimitedVecDeque::new(1000),
+            ws_shutdown: Arc::new(Notify::new()),
+            breaker: CircuitBreaker::default(),
+            max_batch_size: None,
+            tags: Vec::new(),
+            history_depth: HistoryDepth::Unknown,
+            learned_history: None,
+            admin_override: false,
         }
     }
 }
@@ -128,26 +336,134 @@ impl Default for Rpc {
 impl Rpc {
     pub async fn new(
         url: String,
-        ws_url: String,
+        ws_url: Option<String>,
         chain_id: usize,
         rpc_location: RpcLocation,
         stats_vec_size: usize,
     ) -> Self {
+        // Rpcs without a websocket can only poll their head
+        let head_source = match ws_url {
+            Some(_) => HeadSource::Ws,
+            None => HeadSource::Poll,
+        };
         // Return the Rpc struct
         Self {
+            id: NEXT_RPC_ID.fetch_add(1, Ordering::Relaxed),
             url,
             ws_url,
+            head_source,
             chain_id,
             rpc_location,
+            enabled: true,
             last_block: 0,
             last_block_ts: 0,
             current_ts: 0,
+            ws_connected: false,
+            ws_disconnected_ts: chrono::Utc::now().timestamp_millis() as u64,
+            poll_ok: false,
+            head_overdue: false,
+            recent_blocks: VecDeque::new(),
+            reorgs: 0,
+            last_reorg_depth: 0,
+            minority_fork: false,
             avg_latency: 0.0,
             intra_latencies: LimitedVecDeque::new(stats_vec_size),
             srv_latencies: LimitedVecDeque::new(stats_vec_size),
             arrivals_ts: LimitedVecDeque::new(stats_vec_size),
+            ws_shutdown: Arc::new(Notify::new()),
+            breaker: CircuitBreaker::default(),
+            max_batch_size: None,
+            tags: Vec::new(),
+            history_depth: HistoryDepth::Unknown,
+            learned_history: None,
+            admin_override: false,
         }
     }
+
+    // The last_block of an rpc is only trusted while its websocket or its polling delivers heads on time
+    pub fn head_is_stale(&self) -> bool {
+        self.head_overdue || (!self.ws_connected && !self.poll_ok)
+    }
+
+    // Rpcs with a stale head or on a minority fork are only tried after the others
+    pub fn is_deprioritized(&self) -> bool {
+        self.head_is_stale() || self.minority_fork
+    }
+
+    // Keep the history of the latest blocks, returns the depth of the reorg when the block replaces known ones
+    pub fn record_block(&mut self, block: BlockHeader) -> Option<u64> {
+        // Nodes that do not report hashes cannot be checked
+        if block.hash.is_empty() {
+            return None;
+        }
+
+        // Older than the kept history, i.e. a lagging poll, nothing to compare it with
+        if self.recent_blocks.front().is_some_and(|oldest| block.number < oldest.number) {
+            return None;
+        }
+
+        let mut reorg_depth = None;
+        if let Some(tip) = self.recent_blocks.back() {
+            if block.number <= tip.number {
+                match self.recent_blocks.iter().find(|known| known.number == block.number) {
+                    // Already known, i.e. the same head polled twice
+                    Some(known) if known.hash == block.hash => return None,
+                    _ => reorg_depth = Some(tip.number - block.number + 1),
+                }
+            } else if block.number == tip.number + 1 && block.parent_hash != tip.hash {
+                reorg_depth = Some(1);
+            }
+        }
+
+        if let Some(reorg_depth) = reorg_depth {
+            self.recent_blocks.retain(|known| known.number < block.number);
+            self.reorgs += 1;
+            self.last_reorg_depth = reorg_depth;
+        }
+        self.recent_blocks.push_back(block);
+        while self.recent_blocks.len() > BLOCK_HISTORY_SIZE {
+            self.recent_blocks.pop_front();
+        }
+        reorg_depth
+    }
+
+    // Move the rpc to another chain, the heads, the reorgs and the learned history of the previous
+    // chain would otherwise be compared with the blocks of the new one
+    pub fn set_chain_id(&mut self, chain_id: usize) {
+        if chain_id == self.chain_id {
+            return;
+        }
+        self.chain_id = chain_id;
+        self.last_block = 0;
+        self.last_block_ts = 0;
+        self.current_ts = 0;
+        self.head_overdue = false;
+        self.recent_blocks.clear();
+        self.reorgs = 0;
+        self.last_reorg_depth = 0;
+        self.minority_fork = false;
+        self.learned_history = None;
+    }
+
+    // Record a polled head, current_ts only moves when the block changes so polling the same head
+    // again does not make the rpc look late
+    pub fn record_head(&mut self, block_number: u64, block_ts: u64, current_ts: u64) {
+        if block_number == self.last_block {
+            return;
+        }
+        self.last_block = block_number;
+        self.last_block_ts = block_ts;
+        self.current_ts = current_ts;
+    }
+
+    // Record the latencies of a successful request and refresh the average server latency
+    pub fn record_latencies(&mut self, intra_latency: u64, srv_latency: u64) {
+        self.intra_latencies.push(intra_latency);
+        self.srv_latencies.push(srv_latency);
+        self.arrivals_ts.push(chrono::Utc::now().timestamp_millis() as u64);
+        self.avg_latency = self.srv_latencies.deque.iter().sum::<u64>() as f64
+            / self.srv_latencies.deque.len() as f64;
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -172,4 +488,324 @@ impl LimitedVecDeque {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct EjectionPolicy {
+    pub failure_streak: u32, // consecutive failures that eject the rpc
+    pub error_rate: f64,     // failure ratio over a full window that ejects the rpc
+    pub window: usize,       // number of latest outcomes used for the error rate
+    pub cooldown_ms: u64,    // time the rpc stays ejected before it is half-opened
+}
+
+impl Default for EjectionPolicy {
+    fn default() -> Self {
+        Self {
+            failure_streak: 5,
+            error_rate: 0.5,
+            window: 20,
+            cooldown_ms: 30000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CircuitState {
+    Closed,   // the rpc is routed to
+    Open,     // the rpc is ejected until the cooldown ends
+    HalfOpen, // the cooldown ended, a single trial request decides if the rpc is re-admitted
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreaker {
+    pub consecutive_failures: u32,
+    pub outcomes: LimitedVecDeque, // 1 for a success, 0 for a failure
+    pub ejected_until: u64,        // timestamp in ms, 0 while the circuit is closed
+    pub ejections: u64,            // number of times the rpc has been ejected
+    pub trial_until: u64,          // timestamp in ms the half-open trial is awaited until, 0 when none
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 0,
+            outcomes: LimitedVecDeque::new(EjectionPolicy::default().window),
+            ejected_until: 0,
+            ejections: 0,
+            trial_until: 0,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now: u64) -> CircuitState {
+        if self.ejected_until == 0 {
+            CircuitState::Closed
+        } else if now < self.ejected_until {
+            CircuitState::Open
+        } else {
+            CircuitState::HalfOpen
+        }
+    }
 
+    pub fn is_open(&self, now: u64) -> bool {
+        self.state(now) == CircuitState::Open
+    }
+
+    // A half-open rpc only takes requests until its trial is handed out
+    pub fn is_available(&self, now: u64) -> bool {
+        match self.state(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => now >= self.trial_until,
+        }
+    }
+
+    // Hand out the trial of a half-open rpc, returns false while another request holds it.
+    // The trial expires after the cooldown in case its request is cancelled.
+    pub fn begin_request(&mut self, policy: &EjectionPolicy, now: u64) -> bool {
+        if self.state(now) != CircuitState::HalfOpen {
+            return true;
+        }
+        if now < self.trial_until {
+            return false;
+        }
+        self.trial_until = now + policy.cooldown_ms;
+        true
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.outcomes.deque.is_empty() {
+            return 0.0;
+        }
+        let failures = self.outcomes.deque.iter().filter(|outcome| **outcome == 0).count();
+        failures as f64 / self.outcomes.deque.len() as f64
+    }
+
+    // Record the outcome of a request, returns the new state when it changed
+    pub fn record(&mut self, success: bool, policy: &EjectionPolicy, now: u64) -> Option<CircuitState> {
+        if self.outcomes.limit != policy.window {
+            self.outcomes = LimitedVecDeque::new(policy.window);
+        }
+        let previous_state = self.state(now);
+        // Requests sent before the ejection, or as last resort, do not decide while the circuit is open
+        if previous_state == CircuitState::Open {
+            return None;
+        }
+        self.outcomes.push(success as u64);
+        self.trial_until = 0;
+
+        if success {
+            self.consecutive_failures = 0;
+            if previous_state == CircuitState::Closed {
+                return None;
+            }
+            // A successful trial re-admits the rpc with a clean history
+            self.ejected_until = 0;
+            self.outcomes = LimitedVecDeque::new(policy.window);
+            return Some(CircuitState::Closed);
+        }
+
+        self.consecutive_failures += 1;
+        let window_full = self.outcomes.deque.len() == self.outcomes.limit;
+        let should_eject = match previous_state {
+            CircuitState::Closed => {
+                self.consecutive_failures >= policy.failure_streak
+                    || (window_full && self.error_rate() >= policy.error_rate)
+            },
+            // The trial request failed, back to the cooldown
+            CircuitState::HalfOpen | CircuitState::Open => true,
+        };
+        if !should_eject {
+            return None;
+        }
+        self.ejected_until = now + policy.cooldown_ms;
+        self.ejections += 1;
+        Some(CircuitState::Open)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(number: u64, hash: &str, parent_hash: &str) -> BlockHeader {
+        BlockHeader { number, timestamp: 0, hash: hash.to_string(), parent_hash: parent_hash.to_string() }
+    }
+
+    #[test]
+    fn record_block_follows_the_chain() {
+        let mut rpc = Rpc::default();
+        assert_eq!(rpc.record_block(block(10, "0xa", "0x9")), None);
+        assert_eq!(rpc.record_block(block(11, "0xb", "0xa")), None);
+        // The same head polled twice
+        assert_eq!(rpc.record_block(block(11, "0xb", "0xa")), None);
+        assert_eq!(rpc.recent_blocks.len(), 2);
+        assert_eq!(rpc.reorgs, 0);
+    }
+
+    #[test]
+    fn record_block_reports_the_reorg_depth() {
+        let mut rpc = Rpc::default();
+        rpc.record_block(block(10, "0xa", "0x9"));
+        rpc.record_block(block(11, "0xb", "0xa"));
+        rpc.record_block(block(12, "0xc", "0xb"));
+        // A new block 11 replaces blocks 11 and 12
+        assert_eq!(rpc.record_block(block(11, "0xb2", "0xa")), Some(2));
+        // A child of an unknown parent replaces the tip
+        assert_eq!(rpc.record_block(block(12, "0xc2", "0xother")), Some(1));
+        assert_eq!(rpc.recent_blocks.iter().map(|block| block.hash.as_str()).collect::<Vec<&str>>(), vec!["0xa", "0xb2", "0xc2"]);
+        assert_eq!((rpc.reorgs, rpc.last_reorg_depth), (2, 1));
+    }
+
+    #[test]
+    fn record_block_ignores_missing_hashes_and_keeps_the_latest_blocks() {
+        let mut rpc = Rpc::default();
+        assert_eq!(rpc.record_block(block(1, "", "")), None);
+        assert!(rpc.recent_blocks.is_empty());
+        for number in 0..BLOCK_HISTORY_SIZE as u64 + 5 {
+            rpc.record_block(block(number, &format!("0x{:x}", number + 1), &format!("0x{:x}", number)));
+        }
+        assert_eq!(rpc.recent_blocks.len(), BLOCK_HISTORY_SIZE);
+        assert_eq!(rpc.recent_blocks.front().map(|block| block.number), Some(5));
+        assert_eq!(rpc.reorgs, 0);
+        // A block below the kept history is not a reorg
+        assert_eq!(rpc.record_block(block(4, "0xother", "0x4")), None);
+        assert_eq!((rpc.recent_blocks.len(), rpc.reorgs), (BLOCK_HISTORY_SIZE, 0));
+    }
+
+    #[test]
+    fn set_chain_id_forgets_the_heads_of_the_previous_chain() {
+        let mut rpc = Rpc { chain_id: 1, last_block: 12, minority_fork: true, ..Rpc::default() };
+        rpc.record_block(block(11, "0xb", "0xa"));
+        rpc.record_block(block(12, "0xc", "0xother"));
+        rpc.set_chain_id(1);
+        assert_eq!((rpc.last_block, rpc.recent_blocks.len(), rpc.reorgs), (12, 2, 1));
+
+        rpc.set_chain_id(10);
+        assert_eq!((rpc.chain_id, rpc.last_block, rpc.recent_blocks.len(), rpc.reorgs), (10, 0, 0, 0));
+        assert!(!rpc.minority_fork);
+    }
+
+    #[test]
+    fn from_value_accepts_requests_and_notifications() {
+        let request = JsonRpcRequest::from_value(&json!({"jsonrpc": "2.0", "method": "eth_call", "params": [{}], "id": "a"})).unwrap();
+        assert_eq!((request.method.as_str(), request.id), ("eth_call", Some(json!("a"))));
+        let request = JsonRpcRequest::from_value(&json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "id": null})).unwrap();
+        assert_eq!((request.params.is_none(), request.is_notification()), (true, false));
+        let request = JsonRpcRequest::from_value(&json!({"jsonrpc": "2.0", "method": "eth_subscription", "params": {}})).unwrap();
+        assert!(request.is_notification());
+    }
+
+    #[test]
+    fn from_value_rejects_invalid_requests() {
+        let invalid_requests = [
+            json!([]),
+            json!({"method": "eth_call", "id": 1}),
+            json!({"jsonrpc": "1.0", "method": "eth_call", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": 1, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "eth_call", "params": "0x1", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "eth_call", "id": [1]}),
+        ];
+        for invalid_request in invalid_requests {
+            let app_error = JsonRpcRequest::from_value(&invalid_request).unwrap_err();
+            assert_eq!(app_error.code, ErrorCode::BadRequest, "{}", invalid_request);
+        }
+    }
+
+    fn policy() -> EjectionPolicy {
+        EjectionPolicy { failure_streak: 3, error_rate: 0.5, window: 4, cooldown_ms: 1000 }
+    }
+
+    fn ejected_breaker(policy: &EjectionPolicy) -> CircuitBreaker {
+        let mut breaker = CircuitBreaker::default();
+        breaker.record(false, policy, 0);
+        breaker.record(false, policy, 0);
+        assert_eq!(breaker.record(false, policy, 0), Some(CircuitState::Open));
+        breaker
+    }
+
+    #[test]
+    fn record_ejects_after_failure_streak() {
+        let policy = policy();
+        let breaker = ejected_breaker(&policy);
+        assert_eq!(breaker.state(999), CircuitState::Open);
+        assert_eq!(breaker.state(1000), CircuitState::HalfOpen);
+        assert_eq!(breaker.ejections, 1);
+    }
+
+    #[test]
+    fn record_ejects_on_error_rate_of_full_window() {
+        let policy = EjectionPolicy { failure_streak: 10, ..policy() };
+        let mut breaker = CircuitBreaker::default();
+        assert_eq!(breaker.record(false, &policy, 0), None);
+        assert_eq!(breaker.record(true, &policy, 0), None);
+        assert_eq!(breaker.record(true, &policy, 0), None);
+        // The window is full with half of the requests failed
+        assert_eq!(breaker.record(false, &policy, 0), Some(CircuitState::Open));
+    }
+
+    #[test]
+    fn record_ignores_outcomes_while_open() {
+        let policy = policy();
+        let mut breaker = ejected_breaker(&policy);
+        // A request sent before the ejection succeeds during the cooldown
+        assert_eq!(breaker.record(true, &policy, 500), None);
+        assert_eq!(breaker.state(500), CircuitState::Open);
+        assert_eq!(breaker.record(false, &policy, 500), None);
+        assert_eq!(breaker.ejected_until, 1000);
+    }
+
+    #[test]
+    fn record_readmits_on_successful_trial() {
+        let policy = policy();
+        let mut breaker = ejected_breaker(&policy);
+        assert!(breaker.begin_request(&policy, 1000));
+        assert_eq!(breaker.record(true, &policy, 1100), Some(CircuitState::Closed));
+        assert_eq!(breaker.state(1100), CircuitState::Closed);
+        assert!(breaker.outcomes.deque.is_empty());
+        assert_eq!(breaker.trial_until, 0);
+    }
+
+    #[test]
+    fn record_reopens_on_failed_trial() {
+        let policy = policy();
+        let mut breaker = ejected_breaker(&policy);
+        assert!(breaker.begin_request(&policy, 1000));
+        assert_eq!(breaker.record(false, &policy, 1100), Some(CircuitState::Open));
+        assert_eq!(breaker.ejected_until, 2100);
+        assert_eq!(breaker.ejections, 2);
+        assert_eq!(breaker.trial_until, 0);
+    }
+
+    #[test]
+    fn begin_request_hands_out_a_single_trial() {
+        let policy = policy();
+        let mut breaker = ejected_breaker(&policy);
+        assert!(breaker.is_available(1000));
+        assert!(breaker.begin_request(&policy, 1000));
+        // Other requests skip the rpc until the trial is decided
+        assert!(!breaker.is_available(1001));
+        assert!(!breaker.begin_request(&policy, 1001));
+        // A trial that never came back expires after the cooldown
+        assert!(breaker.is_available(2000));
+        assert!(breaker.begin_request(&policy, 2000));
+    }
+
+    #[test]
+    fn begin_request_always_accepts_closed_rpcs() {
+        let policy = policy();
+        let mut breaker = CircuitBreaker::default();
+        assert!(breaker.begin_request(&policy, 0));
+        assert!(breaker.begin_request(&policy, 0));
+        assert_eq!(breaker.trial_until, 0);
+    }
+}
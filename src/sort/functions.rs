@@ -7,9 +7,20 @@ use crate::{
     sort::types::Algo,
 };
 
-use log::{info};
+use log::{info, warn};
 
 pub fn sort_rpc_list_by_algo(algo: Algo, filtered_rpc_list: Vec<&Rpc>) -> Vec<&Rpc> {
+    let filtered_rpc_list = exclude_ejected(filtered_rpc_list);
+    // RPCs with stale head information or on a minority fork go after the others whatever their last_block
+    let (fresh, stale): (Vec<&Rpc>, Vec<&Rpc>) = filtered_rpc_list
+        .into_iter()
+        .partition(|rpc| !rpc.is_deprioritized());
+    let mut sorted_rpc_list = sort_by_algo(algo.clone(), fresh);
+    sorted_rpc_list.extend(sort_by_algo(algo, stale));
+    sorted_rpc_list
+}
+
+pub fn sort_by_algo(algo: Algo, filtered_rpc_list: Vec<&Rpc>) -> Vec<&Rpc> {
     match algo {
         Algo::MinLatency => {
             // Sort the RPC list by block number (descending), RpcLocation local preference and timestamp (ascending)
@@ -19,14 +30,55 @@ pub fn sort_rpc_list_by_algo(algo: Algo, filtered_rpc_list: Vec<&Rpc>) -> Vec<&R
             // Sort the RPC list by block number (descending), RpcLocation local preference and rpc_requests_per_minute (ascending)
             round_robin_sort(filtered_rpc_list)
         },
-        // Add other Algo variants here
-        _ => {
-            // Default Sort the RPC list by block number (ascending) and timestamp (ascending)
+        Algo::Broadcast => {
+            // Broadcast ranks the candidates the same way as min_latency, see broadcast_targets
             min_latency_sort(filtered_rpc_list)
         },
     }
 }
 
+pub fn exclude_ejected(filtered_rpc_list: Vec<&Rpc>) -> Vec<&Rpc> {
+    // Drop the RPCs with an open circuit or a trial in flight, unless none is left to route to
+    let now = chrono::Utc::now().timestamp_millis() as u64;
+    let (available, ejected): (Vec<&Rpc>, Vec<&Rpc>) = filtered_rpc_list
+        .into_iter()
+        .partition(|rpc| rpc.breaker.is_available(now));
+    if available.is_empty() && !ejected.is_empty() {
+        warn!("All {} candidate RPCs are ejected, using them as last resort", ejected.len());
+        return ejected;
+    }
+    available
+}
+
+pub fn chain_head(filtered_rpc_list: &[&Rpc]) -> u64 {
+    // The chain head is the highest block among the RPCs with a trusted head
+    filtered_rpc_list
+        .iter()
+        .filter(|rpc| !rpc.is_deprioritized())
+        .map(|rpc| rpc.last_block)
+        .max()
+        .unwrap_or_default()
+}
+
+pub fn split_by_block_lag(filtered_rpc_list: Vec<&Rpc>, max_block_lag: u64) -> (u64, Vec<&Rpc>, Vec<&Rpc>) {
+    let chain_head = chain_head(&filtered_rpc_list);
+    let (within_lag, lagging) = filtered_rpc_list
+        .into_iter()
+        .partition(|rpc| chain_head.saturating_sub(rpc.last_block) <= max_block_lag);
+    (chain_head, within_lag, lagging)
+}
+
+pub fn broadcast_targets(selection: Algo, filtered_rpc_list: Vec<&Rpc>, broadcast_size: usize) -> Vec<&Rpc> {
+    // Rank the RPC list with the selection algorithm and keep the top `broadcast_size` nodes
+    let selection = match selection {
+        Algo::Broadcast => Algo::MinLatency,
+        selection => selection,
+    };
+    let mut sorted_rpc_list = sort_rpc_list_by_algo(selection, filtered_rpc_list);
+    sorted_rpc_list.truncate(broadcast_size.max(1));
+    sorted_rpc_list
+}
+
 pub fn min_latency_sort(mut filtered_rpc_list: Vec<&Rpc>) -> Vec<&Rpc> {
     // Sort the RPC list by block number (descending), RpcLocation::Local preference, and timestamp (ascending)
     filtered_rpc_list.sort_by(|a, b| {
@@ -48,6 +100,10 @@ pub fn rpc_requests_per_minute(arrivals_ts: &LimitedVecDeque) -> f64 {
 
     let first_arrival_ts = *arrivals_ts.deque.back().unwrap();
     let last_arrival_ts = *arrivals_ts.deque.front().unwrap();
+    // Arrivals within the same millisecond all fall in the last minute, the rate would be infinite
+    if last_arrival_ts <= first_arrival_ts {
+        return arrivals_ts.deque.len() as f64;
+    }
     let difference_in_minutes = (last_arrival_ts - first_arrival_ts) as f64 / 60000.0;
 
     arrivals_ts.deque.len() as f64 / difference_in_minutes
@@ -77,4 +133,44 @@ pub fn compare_rpc_location(a: &RpcLocation, b: &RpcLocation) -> std::cmp::Order
         (RpcLocation::External, RpcLocation::Local) => std::cmp::Ordering::Greater,
         _ => std::cmp::Ordering::Equal,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(id: usize, last_block: u64) -> Rpc {
+        Rpc { id, last_block, poll_ok: true, ..Rpc::default() }
+    }
+
+    #[test]
+    fn rpc_requests_per_minute_counts_arrivals_of_the_same_millisecond() {
+        let mut arrivals_ts = LimitedVecDeque::new(10);
+        arrivals_ts.push(1000);
+        arrivals_ts.push(1000);
+        arrivals_ts.push(1000);
+        assert_eq!(rpc_requests_per_minute(&arrivals_ts), 3.0);
+        arrivals_ts.push(61000);
+        assert_eq!(rpc_requests_per_minute(&arrivals_ts), 4.0);
+    }
+
+    #[test]
+    fn split_by_block_lag_measures_from_the_fresh_heads() {
+        let mut stale = rpc(4, 200);
+        stale.poll_ok = false;
+        let rpc_list = [rpc(1, 100), rpc(2, 98), rpc(3, 97), stale];
+        let (chain_head, within_lag, lagging) = split_by_block_lag(rpc_list.iter().collect(), 2);
+        // The stale rpc does not move the chain head and is within the lag of it
+        assert_eq!(chain_head, 100);
+        assert_eq!(within_lag.iter().map(|rpc| rpc.id).collect::<Vec<usize>>(), vec![1, 2, 4]);
+        assert_eq!(lagging.iter().map(|rpc| rpc.id).collect::<Vec<usize>>(), vec![3]);
+    }
+
+    #[test]
+    fn split_by_block_lag_keeps_everything_without_fresh_heads() {
+        let mut stale = rpc(1, 50);
+        stale.poll_ok = false;
+        let (chain_head, within_lag, lagging) = split_by_block_lag(vec![&stale], 0);
+        assert_eq!((chain_head, within_lag.len(), lagging.len()), (0, 1, 0));
+    }
+}
@@ -1,7 +1,8 @@
 use std::str::FromStr;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum Algo {
+    #[default]
     MinLatency,
     RoundRobin,
     Broadcast,
@@ -20,8 +21,31 @@ impl FromStr for Algo {
     }
 }
 
-impl Default for Algo {
-    fn default() -> Self {
-        Algo::MinLatency
+// What to do with the RPCs lagging more than max_block_lag behind the chain head
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum BlockLagPolicy {
+    #[default]
+    Exclude,    // never used for reads
+    LastResort, // only tried after all the other RPCs failed
+}
+
+impl BlockLagPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockLagPolicy::Exclude => "excluded",
+            BlockLagPolicy::LastResort => "kept as last resort",
+        }
     }
-}
\ No newline at end of file
+}
+
+impl FromStr for BlockLagPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exclude" => Ok(BlockLagPolicy::Exclude),
+            "last_resort" => Ok(BlockLagPolicy::LastResort),
+            _ => Err(()),
+        }
+    }
+}
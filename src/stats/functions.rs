@@ -0,0 +1,140 @@
+use crate::{
+    rpc::{
+        errors::{
+            ApplicationError,
+            ErrorCode,
+            JsonRpcErrorResponse,
+        },
+        types::{
+            LimitedVecDeque,
+            Rpc,
+        },
+    },
+    sort::functions::rpc_requests_per_minute,
+    stats::types::{
+        ChainStats,
+        CircuitStats,
+        LatencyStats,
+        RpcStats,
+    },
+};
+
+use hyper::Response;
+use log::{debug, error};
+use std::sync::{Arc, Mutex};
+
+pub fn get_stats(rpc_list: Arc<Mutex<Vec<Rpc>>>, query: Option<&str>) -> Result<Response<String>, hyper::Error> {
+    // Optional ?chain_id= filter, 0 means every chain
+    let chain_id = match extract_query_param(query, "chain_id") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(chain_id) => chain_id,
+            Err(_) => {
+                let json_response = JsonRpcErrorResponse::from(ApplicationError::new(
+                    ErrorCode::BadRequest,
+                    format!("Invalid chain_id query parameter: {}", value),
+                ));
+                error!("Error: {}", json_response.error.format_error().as_str());
+                return Ok(json_response.to_response());
+            }
+        },
+        None => 0,
+    };
+
+    let rpc_list_copy = {
+        let rpc_guard = rpc_list.lock().unwrap();
+        rpc_guard.clone()
+    };
+
+    let stats = build_chain_stats(&rpc_list_copy, chain_id);
+    debug!("stats: {:?}", stats);
+    Ok(Response::new(serde_json::to_string(&stats).unwrap()))
+}
+
+pub fn build_chain_stats(rpc_list: &[Rpc], chain_id: usize) -> ChainStats {
+    let mut stats = ChainStats::new();
+    for rpc in rpc_list.iter().filter(|rpc| chain_id == 0 || rpc.chain_id == chain_id) {
+        stats
+            .entry(rpc.chain_id)
+            .or_default()
+            .insert(rpc.url.clone(), build_rpc_stats(rpc));
+    }
+    stats
+}
+
+pub fn build_rpc_stats(rpc: &Rpc) -> RpcStats {
+    RpcStats {
+        id: rpc.id,
+        enabled: rpc.enabled,
+        rpc_location: format!("{:?}", rpc.rpc_location),
+        last_block: rpc.last_block,
+        head_stale: rpc.head_is_stale(),
+        minority_fork: rpc.minority_fork,
+        reorgs: rpc.reorgs,
+        last_reorg_depth: rpc.last_reorg_depth,
+        requests_per_minute: rpc_requests_per_minute(&rpc.arrivals_ts),
+        intra_latency: latency_stats(&rpc.intra_latencies),
+        srv_latency: latency_stats(&rpc.srv_latencies),
+        circuit: CircuitStats {
+            state: rpc.breaker.state(chrono::Utc::now().timestamp_millis() as u64).as_str().to_string(),
+            consecutive_failures: rpc.breaker.consecutive_failures,
+            error_rate: rpc.breaker.error_rate(),
+            ejected_until: rpc.breaker.ejected_until,
+            ejections: rpc.breaker.ejections,
+        },
+    }
+}
+
+pub fn latency_stats(latencies: &LimitedVecDeque) -> LatencyStats {
+    if latencies.deque.is_empty() {
+        return LatencyStats::default();
+    }
+
+    let mut sorted: Vec<u64> = latencies.deque.iter().copied().collect();
+    sorted.sort_unstable();
+    let len = sorted.len();
+
+    let median = if len.is_multiple_of(2) {
+        (sorted[len / 2 - 1] + sorted[len / 2]) as f64 / 2.0
+    } else {
+        sorted[len / 2] as f64
+    };
+
+    LatencyStats {
+        samples: len,
+        average: sorted.iter().sum::<u64>() as f64 / len as f64,
+        median,
+        p50: percentile(&sorted, 50.0),
+        p90: percentile(&sorted, 90.0),
+        p99: percentile(&sorted, 99.0),
+        max: sorted[len - 1],
+    }
+}
+
+pub fn percentile(sorted: &[u64], percentile: f64) -> u64 {
+    // Nearest-rank percentile over an already sorted slice
+    let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub fn extract_query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_uses_the_nearest_rank() {
+        let sorted: Vec<u64> = (1..=10).collect();
+        assert_eq!(percentile(&sorted, 50.0), 5);
+        assert_eq!(percentile(&sorted, 90.0), 9);
+        assert_eq!(percentile(&sorted, 99.0), 10);
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&[7], 99.0), 7);
+    }
+}
@@ -0,0 +1,41 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LatencyStats {
+    pub samples: usize, // number of latencies in the window
+    pub average: f64,   // all values are expressed in μs
+    pub median: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RpcStats {
+    pub id: usize,
+    pub enabled: bool,
+    pub rpc_location: String,
+    pub last_block: u64,
+    pub head_stale: bool,
+    pub minority_fork: bool,
+    pub reorgs: u64,
+    pub last_reorg_depth: u64,
+    pub requests_per_minute: f64,
+    pub intra_latency: LatencyStats,
+    pub srv_latency: LatencyStats,
+    pub circuit: CircuitStats,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CircuitStats {
+    pub state: String, // closed, open or half_open
+    pub consecutive_failures: u32,
+    pub error_rate: f64,    // failure ratio over the ejection window
+    pub ejected_until: u64, // timestamp in ms, 0 while the circuit is closed
+    pub ejections: u64,
+}
+
+// Stats grouped by chain_id and then by rpc url
+pub type ChainStats = BTreeMap<usize, BTreeMap<String, RpcStats>>;
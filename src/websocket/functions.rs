@@ -0,0 +1,15 @@
+use crate::{
+    rpc::types::Rpc,
+    websocket::types::RpcWebSocket,
+};
+
+use std::sync::{Arc, Mutex};
+
+pub async fn start_rpc_websocket(rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize, ws_url: String) {
+    // Connect to the rpc websocket and keep its head information updated in the rpc list
+    let mut rpc_websocket_clone = RpcWebSocket::new(ws_url);
+    tokio::task::spawn(async move {
+        rpc_websocket_clone.start_rpc(rpc_list, rpc_id)
+            .await;
+    });
+}
@@ -1 +1,2 @@
 pub mod types;
+pub mod functions;
@@ -1,4 +1,8 @@
 use crate::{
+    head::{
+        functions::track_block,
+        types::BlockHeader,
+    },
     rpc::types::{
         Rpc,
         JsonRpcRequest,
@@ -7,15 +11,28 @@ use crate::{
 
 use futures_util::sink::SinkExt;
 use futures_util::StreamExt;
-use serde_json::{Value};
+use rand::Rng;
+use serde_json::{json, Value};
 use std::sync::{Arc, Mutex};
-use log::{debug, error, info};
+use std::time::Duration;
+use log::{debug, error, info, warn};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::{connect_async, MaybeTlsStream};
 
+// Reconnection delays, doubled after every failed attempt and capped
+pub const WS_RECONNECT_MIN_DELAY_MS: u64 = 500;
+pub const WS_RECONNECT_MAX_DELAY_MS: u64 = 30000;
+pub const WS_CONNECT_TIMEOUT_MS: u64 = 10000;
+
+#[derive(Debug, PartialEq)]
+pub enum WsExit {
+    Shutdown,     // the rpc was removed, stop for good
+    Disconnected, // the connection was lost, reconnect
+}
+
 #[derive(Debug, Clone)]
 pub struct RpcWebSocket {
     pub ws_url: String, // url of the rpc
@@ -32,63 +49,139 @@ impl Default for RpcWebSocket {
 }
 
 impl RpcWebSocket {
-    pub async fn new(ws_url: String) -> Self {
-        info!("Creating new WS connection to: {}", ws_url);
-        // Connect to the websocket
-        let (ws_stream, _) = connect_async(&ws_url).await.expect("Failed to connect");
-
-        // Return the Rpc struct
+    pub fn new(ws_url: String) -> Self {
+        // The connection is established by start_rpc, so an unreachable node does not stop the balancer
         Self {
             ws_url,
-            ws_stream: Arc::new(RwLock::new(Some(ws_stream))),
+            ws_stream: Arc::new(RwLock::new(None)),
         }
     }
 
-    pub async fn subscribe_to_new_heads(&self) {
+    pub async fn connect(&self) -> Result<(), String> {
+        info!("Creating new WS connection to: {}", self.ws_url);
+        let connection = tokio::time::timeout(
+            Duration::from_millis(WS_CONNECT_TIMEOUT_MS),
+            connect_async(&self.ws_url),
+        ).await;
+        let ws_stream = match connection {
+            Ok(Ok((ws_stream, _))) => ws_stream,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("timed out after {} ms", WS_CONNECT_TIMEOUT_MS)),
+        };
+        *self.ws_stream.write().await = Some(ws_stream);
+        Ok(())
+    }
+
+    pub async fn subscribe_to_new_heads(&self) -> Result<(), String> {
         let request = JsonRpcRequest {
             jsonrpc: "2.0".to_string(),
             method: "eth_subscribe".to_string(),
-            params: vec![serde_json::Value::String("newHeads".to_string())],
-            id: Value::Null,
+            params: Some(json!(["newHeads"])),
+            id: Some(Value::Null),
         };
 
         let request_json = serde_json::to_string(&request).unwrap();
 
         let mut ws_stream_guard = self.ws_stream.write().await;
-        if let Some(ws_stream) = ws_stream_guard.as_mut() {
-            ws_stream.send(Message::Text(request_json)).await.unwrap();
+        match ws_stream_guard.as_mut() {
+            Some(ws_stream) => ws_stream.send(Message::Text(request_json)).await.map_err(|e| e.to_string()),
+            None => Err("not connected".to_string()),
         }
     }
 
-    pub async fn start_rpc(&mut self, rpc_list: Arc<Mutex<Vec<Rpc>>>, index_rpc: usize) {
-        self.subscribe_to_new_heads().await;
-        let mut cloned_self = self.clone();
-        tokio::task::spawn(async move { cloned_self.listen_for_updates(rpc_list, index_rpc).await });
-    }
+    pub async fn start_rpc(&mut self, rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize) {
+        let ws_shutdown = {
+            let rpc_guard = rpc_list.lock().unwrap();
+            match rpc_guard.iter().find(|rpc| rpc.id == rpc_id) {
+                Some(rpc) => rpc.ws_shutdown.clone(),
+                // The rpc was removed before its websocket was started
+                None => return,
+            }
+        };
 
-    pub async fn listen_for_updates(&mut self, rpc_list: Arc<Mutex<Vec<Rpc>>>, index_rpc: usize) {
+        // Keep the newHeads subscription alive, reconnecting with exponential backoff and jitter
+        let mut reconnect_delay_ms = WS_RECONNECT_MIN_DELAY_MS;
         loop {
-            // Get the websocket stream
-            let mut ws_stream = self.ws_stream.write().await;
-            if let Some(stream) = ws_stream.as_mut() {
-                while let Some(msg) = stream.next().await {
-                    match msg {
-                        Ok(msg) => {
-                            self.process_message(msg.clone(), rpc_list.clone(), index_rpc).await;
-                        }
-                        Err(e) => error!("Error reading message: {}", e),
+            let connection = match self.connect().await {
+                Ok(()) => self.subscribe_to_new_heads().await,
+                Err(e) => Err(e),
+            };
+            match connection {
+                Ok(()) => {
+                    info!("Subscribed to new heads on: {}", self.ws_url);
+                    reconnect_delay_ms = WS_RECONNECT_MIN_DELAY_MS;
+                    if self.listen_for_updates(rpc_list.clone(), rpc_id, ws_shutdown.clone()).await == WsExit::Shutdown {
+                        return;
                     }
+                },
+                Err(e) => error!("Error connecting to {}: {}", self.ws_url, e),
+            }
+
+            // Until the subscription is restored the head information of this rpc is stale
+            if let Some(rpc) = rpc_list.lock().unwrap().iter_mut().find(|rpc| rpc.id == rpc_id) {
+                if rpc.ws_connected {
+                    rpc.ws_disconnected_ts = chrono::Utc::now().timestamp_millis() as u64;
                 }
+                rpc.ws_connected = false;
+            }
+            *self.ws_stream.write().await = None;
+
+            let delay_ms = rand::thread_rng().gen_range(reconnect_delay_ms / 2..=reconnect_delay_ms);
+            warn!("WS connection to {} unavailable, reconnecting in {} ms", self.ws_url, delay_ms);
+            tokio::select! {
+                _ = ws_shutdown.notified() => {
+                    info!("Stopped reconnecting to: {}", self.ws_url);
+                    return;
+                },
+                _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => {},
+            }
+            reconnect_delay_ms = (reconnect_delay_ms * 2).min(WS_RECONNECT_MAX_DELAY_MS);
+        }
+    }
+
+    pub async fn listen_for_updates(&mut self, rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize,
+                                    ws_shutdown: Arc<Notify>) -> WsExit {
+        // Get the websocket stream
+        let mut ws_stream = self.ws_stream.write().await;
+        let stream = match ws_stream.as_mut() {
+            Some(stream) => stream,
+            None => return WsExit::Disconnected,
+        };
+        loop {
+            tokio::select! {
+                _ = ws_shutdown.notified() => {
+                    info!("Closing WS connection to: {}", self.ws_url);
+                    if let Err(e) = stream.close(None).await {
+                        debug!("Error closing WS connection to {}: {}", self.ws_url, e);
+                    }
+                    return WsExit::Shutdown;
+                },
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Close(frame))) => {
+                        info!("WS connection to {} closed by the node: {:?}", self.ws_url, frame);
+                        return WsExit::Disconnected;
+                    },
+                    Some(Ok(msg)) => {
+                        self.process_message(msg.clone(), rpc_list.clone(), rpc_id).await;
+                    },
+                    Some(Err(e)) => {
+                        error!("Error reading message from {}: {}", self.ws_url, e);
+                        return WsExit::Disconnected;
+                    },
+                    None => return WsExit::Disconnected,
+                },
             }
         }
     }
 
-    pub async fn process_message(&self, msg: Message, rpc_list: Arc<Mutex<Vec<Rpc>>>, index_rpc: usize) {
+    pub async fn process_message(&self, msg: Message, rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize) {
         match msg.is_text() {
             true => {
                 let text = msg.into_text().unwrap();
-                let value: Value = serde_json::from_str(&text).unwrap();
-                self.process_params(&value, rpc_list.clone(), index_rpc).await;
+                match serde_json::from_str::<Value>(&text) {
+                    Ok(value) => self.process_params(&value, rpc_list.clone(), rpc_id).await,
+                    Err(e) => error!("Invalid message from {}: {}", self.ws_url, e),
+                }
             }
             false => match msg {
                 Message::Ping(ping) => debug!("Received Ping: {:?}", ping),
@@ -97,33 +190,32 @@ impl RpcWebSocket {
         }
     }
 
-    pub async fn process_params(&self, value: &Value, rpc_list: Arc<Mutex<Vec<Rpc>>>, index_rpc: usize) {
-        if value.get("method").map_or(false, |m| m.as_str() == Some("eth_subscription")) {
-            let result = value.get("params").and_then(|p| p.get("result"));
-
-            let block_number = u64::from_str_radix(
-                result
-                    .and_then(|r| r.get("number"))
-                    .and_then(|n| n.as_str())
-                    .map_or("", |n| n.trim_start_matches("0x")), 16).unwrap();
-
-            let timestamp = u64::from_str_radix(
-                result
-                    .and_then(|r| r.get("timestamp"))
-                    .and_then(|t| t.as_str())
-                    .map_or("", |t| t.trim_start_matches("0x")), 16).unwrap() * 1000;
+    pub async fn process_params(&self, value: &Value, rpc_list: Arc<Mutex<Vec<Rpc>>>, rpc_id: usize) {
+        if value.get("method").is_some_and(|m| m.as_str() == Some("eth_subscription")) {
+            let head = match value.get("params").and_then(|p| p.get("result")).and_then(BlockHeader::from_value) {
+                Some(head) => head,
+                None => {
+                    error!("Invalid new head from {}: {}", self.ws_url, value);
+                    return;
+                },
+            };
 
             let current_timestamp = chrono::Utc::now().timestamp_millis() as u64;
             {
                 let mut rpc_guard = rpc_list.lock().unwrap();
-                rpc_guard[index_rpc].last_block = block_number;
-                rpc_guard[index_rpc].last_block_ts = timestamp;
-                rpc_guard[index_rpc].current_ts = current_timestamp;
-
-                debug!("Rpc updated url: {:?}", rpc_guard[index_rpc].url);
-                debug!("Rpc updated last block: {:?}", rpc_guard[index_rpc].last_block);
-                debug!("Rpc updated last block ts: {:?}", rpc_guard[index_rpc].last_block_ts);
-                debug!("Rpc updated current ts: {:?}", rpc_guard[index_rpc].current_ts);
+                if let Some(rpc) = rpc_guard.iter_mut().find(|rpc| rpc.id == rpc_id) {
+                    rpc.last_block = head.number;
+                    rpc.last_block_ts = head.timestamp * 1000;
+                    rpc.current_ts = current_timestamp;
+                    track_block(rpc, head);
+                    // Heads are trusted again once the restored subscription delivers one
+                    rpc.ws_connected = true;
+
+                    debug!("Rpc updated url: {:?}", rpc.url);
+                    debug!("Rpc updated last block: {:?}", rpc.last_block);
+                    debug!("Rpc updated last block ts: {:?}", rpc.last_block_ts);
+                    debug!("Rpc updated current ts: {:?}", rpc.current_ts);
+                }
             }
         }
     }